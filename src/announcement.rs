@@ -0,0 +1,102 @@
+use std::path::{Path, PathBuf};
+
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::PeerId;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::health_check::ProviderHealth;
+use crate::service::ApronService;
+
+/// Envelope of gossiped data, signed with the key of the publishing gateway.
+/// The envelope is kept for service entries, so that it can be forwarded to
+/// other gateways while syncing registry and still be verified there.
+#[derive(Deserialize, Debug, Serialize, PartialEq, Clone)]
+pub struct SignedAnnouncement {
+    // Protobuf encoded public key of publisher
+    pub public_key: Vec<u8>,
+    pub payload: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl SignedAnnouncement {
+    pub fn sign(keypair: &Keypair, payload: Vec<u8>) -> Result<Self, String> {
+        let signature = keypair.sign(&payload).map_err(|e| e.to_string())?;
+        Ok(SignedAnnouncement {
+            public_key: keypair.public().into_protobuf_encoding(),
+            payload,
+            signature,
+        })
+    }
+
+    /// Verify signature of the payload, and return the peer id of the signer.
+    pub fn verify(&self) -> Result<PeerId, String> {
+        let public_key = PublicKey::from_protobuf_encoding(&self.public_key)
+            .map_err(|e| format!("invalid public key: {:?}", e))?;
+        if !public_key.verify(&self.payload, &self.signature) {
+            return Err(String::from("invalid signature"));
+        }
+        Ok(public_key.into_peer_id())
+    }
+
+    fn open<T: DeserializeOwned>(&self) -> Result<(PeerId, T), String> {
+        let signer = self.verify()?;
+        let value = serde_json::from_slice(&self.payload).map_err(|e| e.to_string())?;
+        Ok((signer, value))
+    }
+
+    /// Verify and decode service entry, which must be signed by the peer made it.
+    pub fn open_service(&self) -> Result<(PeerId, ApronService), String> {
+        let (signer, service): (PeerId, ApronService) = self.open()?;
+        if service.origin_peer_id != Some(signer.to_base58()) {
+            return Err(format!(
+                "service {} made by {:?} is signed by {}",
+                service.id, service.origin_peer_id, signer
+            ));
+        }
+        Ok((signer, service))
+    }
+
+    /// Verify and decode provider health, which must be signed by the peer checked it.
+    pub fn open_health(&self) -> Result<(PeerId, ProviderHealth), String> {
+        let (signer, health): (PeerId, ProviderHealth) = self.open()?;
+        if health.peer_id != signer.to_base58() {
+            return Err(format!(
+                "health of {} checked by {} is signed by {}",
+                health.key(),
+                health.peer_id,
+                signer
+            ));
+        }
+        Ok((signer, health))
+    }
+}
+
+/// File keeping signed announcements of services, next to the registry file.
+pub fn announcements_path(registry_path: &Path) -> PathBuf {
+    let mut path = registry_path.as_os_str().to_owned();
+    path.push(".announcements");
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::generate_peer_id_from_seed;
+
+    #[test]
+    fn test_verify_signer() {
+        let (keypair, peer_id) = generate_peer_id_from_seed(Some(1));
+        let announcement = SignedAnnouncement::sign(&keypair, b"payload".to_vec()).unwrap();
+        assert_eq!(announcement.verify(), Ok(peer_id));
+
+        let mut tampered = announcement.clone();
+        tampered.payload = b"tampered".to_vec();
+        assert!(tampered.verify().is_err());
+
+        let (other_keypair, _) = generate_peer_id_from_seed(Some(2));
+        let mut forged = announcement;
+        forged.public_key = other_keypair.public().into_protobuf_encoding();
+        assert!(forged.verify().is_err());
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::web::{Data, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+// Consecutive failures to open the circuit
+const FAILURE_THRESHOLD: u32 = 5;
+// Time the circuit stays open before a trial request is let through
+const OPEN_SECS: u64 = 30;
+
+#[derive(Deserialize, Debug, Serialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    // Requests are rejected without being sent
+    Open,
+    // One trial request is let through, which decides whether to close the circuit
+    HalfOpen,
+}
+
+/// Failure state of one upstream, only upstreams which failed recently are tracked.
+#[derive(Deserialize, Debug, Serialize, PartialEq, Clone)]
+pub struct Circuit {
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    // Time the circuit was opened or the trial request was let through
+    pub changed_at: u64,
+    pub last_error: Option<String>,
+}
+
+/// Circuits keyed by upstream.
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl CircuitBreaker {
+    /// Whether a request can be sent to the upstream now.
+    pub fn allow(&self, key: &str) -> bool {
+        self.allow_at(key, now_secs())
+    }
+
+    /// Whether requests to the upstream are rejected now, unlike `allow` no trial is let through.
+    pub fn is_open(&self, key: &str) -> bool {
+        self.is_open_at(key, now_secs())
+    }
+
+    fn is_open_at(&self, key: &str, now: u64) -> bool {
+        let circuits = self.circuits.lock().expect("Could not acquire lock");
+        circuits.get(key).map_or(false, |circuit| {
+            circuit.state != CircuitState::Closed
+                && now.saturating_sub(circuit.changed_at) < OPEN_SECS
+        })
+    }
+
+    fn allow_at(&self, key: &str, now: u64) -> bool {
+        let mut circuits = self.circuits.lock().expect("Could not acquire lock");
+        let circuit = match circuits.get_mut(key) {
+            Some(circuit) => circuit,
+            None => return true,
+        };
+        match circuit.state {
+            CircuitState::Closed => true,
+            // Another trial is let through if the previous one never reported back
+            CircuitState::Open | CircuitState::HalfOpen => {
+                if now.saturating_sub(circuit.changed_at) < OPEN_SECS {
+                    return false;
+                }
+                circuit.state = CircuitState::HalfOpen;
+                circuit.changed_at = now;
+                true
+            }
+        }
+    }
+
+    /// Record result of a request sent to the upstream.
+    pub fn record(&self, key: &str, result: Result<(), String>) {
+        self.record_at(key, result, now_secs())
+    }
+
+    fn record_at(&self, key: &str, result: Result<(), String>, now: u64) {
+        let mut circuits = self.circuits.lock().expect("Could not acquire lock");
+        let error = match result {
+            Ok(()) => {
+                circuits.remove(key);
+                return;
+            }
+            Err(error) => error,
+        };
+
+        let circuit = circuits.entry(key.to_string()).or_insert(Circuit {
+            state: CircuitState::Closed,
+            consecutive_failures: 0,
+            changed_at: now,
+            last_error: None,
+        });
+        circuit.consecutive_failures += 1;
+        circuit.last_error = Some(error);
+        // Open circuit keeps its time, late failures of requests sent before don't extend it
+        if circuit.state == CircuitState::HalfOpen
+            || (circuit.state == CircuitState::Closed
+                && circuit.consecutive_failures >= FAILURE_THRESHOLD)
+        {
+            circuit.state = CircuitState::Open;
+            circuit.changed_at = now;
+        }
+    }
+
+    /// Circuits which are not closed.
+    pub fn tripped(&self) -> HashMap<String, Circuit> {
+        self.circuits
+            .lock()
+            .expect("Could not acquire lock")
+            .iter()
+            .filter(|(_, circuit)| circuit.state != CircuitState::Closed)
+            .map(|(key, circuit)| (key.clone(), circuit.clone()))
+            .collect()
+    }
+}
+
+/// Circuits of providers requested by this gateway, keyed by provider key,
+/// and of service side gateways, keyed by peer id.
+#[derive(Debug, Default)]
+pub struct CircuitBreakers {
+    pub providers: CircuitBreaker,
+    pub peers: CircuitBreaker,
+}
+
+/// Whether response status means the upstream failed, rather than the request.
+pub fn is_upstream_failure(status_code: u16) -> bool {
+    matches!(status_code, 502..=504)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[derive(Serialize)]
+struct TrippedCircuits {
+    providers: HashMap<String, Circuit>,
+    peers: HashMap<String, Circuit>,
+}
+
+/// List circuits currently open or half open
+pub async fn list_tripped_circuits(breakers: Data<CircuitBreakers>) -> HttpResponse {
+    println!("[mgmt]: List tripped circuits");
+    HttpResponse::Ok().json(TrippedCircuits {
+        providers: breakers.providers.tripped(),
+        peers: breakers.peers.tripped(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_and_half_open() {
+        let breaker = CircuitBreaker::default();
+        for _ in 0..FAILURE_THRESHOLD - 1 {
+            breaker.record_at("p1", Err("refused".to_string()), 100);
+        }
+        assert!(breaker.allow_at("p1", 100));
+        assert!(breaker.tripped().is_empty());
+
+        breaker.record_at("p1", Err("refused".to_string()), 100);
+        assert!(breaker.is_open_at("p1", 101));
+        assert!(!breaker.allow_at("p1", 101));
+        assert_eq!(breaker.tripped()["p1"].state, CircuitState::Open);
+
+        // Only one trial is let through, failed trial opens the circuit again
+        assert!(!breaker.is_open_at("p1", 100 + OPEN_SECS));
+        assert!(breaker.allow_at("p1", 100 + OPEN_SECS));
+        assert!(breaker.is_open_at("p1", 100 + OPEN_SECS));
+        assert!(!breaker.allow_at("p1", 101 + OPEN_SECS));
+        breaker.record_at("p1", Err("refused".to_string()), 101 + OPEN_SECS);
+        assert!(!breaker.allow_at("p1", 102 + OPEN_SECS));
+
+        assert!(breaker.allow_at("p1", 101 + 2 * OPEN_SECS));
+        breaker.record_at("p1", Ok(()), 101 + 2 * OPEN_SECS);
+        assert!(breaker.allow_at("p1", 102 + 2 * OPEN_SECS));
+        assert!(breaker.tripped().is_empty());
+    }
+}
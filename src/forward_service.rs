@@ -4,7 +4,9 @@ use actix_web::{middleware, web, App, HttpServer};
 use futures::channel::mpsc;
 use log::{info, warn};
 
+use crate::circuit_breaker::CircuitBreakers;
 use crate::fwd_handlers::{forward_http_proxy_request, forward_ws_proxy_request};
+use crate::load_balancer::ProviderSelector;
 use crate::service::SharedHandler;
 use crate::state::AppState;
 use crate::{ApronService, HttpProxyResponse, PeerId};
@@ -16,6 +18,9 @@ pub struct ForwardService {
     pub p2p_handler: web::Data<SharedHandler>,
     pub peer_id: PeerId,
     pub req_id_client_session_mapping: AppState<mpsc::Sender<HttpProxyResponse>>,
+    pub circuit_breakers: web::Data<CircuitBreakers>,
+    // Picks providers of local services, which are served by this gateway directly
+    pub provider_selector: web::Data<ProviderSelector>,
 }
 
 impl ForwardService {
@@ -28,15 +33,22 @@ impl ForwardService {
         HttpServer::new(move || {
             App::new()
                 .wrap(middleware::Logger::default())
-                .wrap(middleware::NormalizePath::default())
                 .app_data(self.service_data.clone())
                 .app_data(self.p2p_handler.clone())
                 .app_data(app_data_peer_id.clone())
                 .app_data(self.req_id_client_session_mapping.clone())
+                .app_data(self.circuit_breakers.clone())
+                .app_data(self.provider_selector.clone())
+                // Paths are forwarded as sent by client, so they are not normalized
+                .route("/v{ver}/{user_key}", web::to(forward_http_proxy_request))
                 .route(
                     "/v{ver}/{user_key}/{req_path:.*}",
                     web::to(forward_http_proxy_request),
                 )
+                .route(
+                    "/ws/v{ver}/{user_key}",
+                    web::get().to(forward_ws_proxy_request),
+                )
                 .route(
                     "/ws/v{ver}/{user_key}/{req_path:.*}",
                     web::get().to(forward_ws_proxy_request),
@@ -1,40 +1,201 @@
 use std::string::String;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use actix::io::SinkWrite;
 use actix::*;
 use actix_codec::Framed;
+use actix_http::ws::Item;
 use actix_web::web::{Bytes, Data};
 use actix_web_actors::ws;
 use actix_web_actors::ws::WebsocketContext;
 use awc::error::WsProtocolError;
-use awc::ws::{Codec, Frame, Message};
+use awc::ws::{CloseCode, CloseReason, Codec, Frame, Message};
 use awc::BoxedSocket;
 use futures::channel::mpsc;
 use futures::channel::mpsc::Sender;
-use futures::executor::block_on;
 use futures::stream::SplitSink;
 use futures::SinkExt;
 use libp2p::PeerId;
-use log::info;
+use log::{info, warn};
 
-use crate::forward_service_models::{ProxyData, ProxyRequestInfo, ServiceUsageData};
+use crate::forward_service_models::{ProxyData, ProxyRequestInfo, ServiceUsageData, WsControl};
+use crate::load_balancer::ProviderLease;
 use crate::network::Command;
-use crate::state::AppState;
+use crate::protocol::MAX_WS_MESSAGE_SIZE;
+use crate::state::{delete, AppState};
 use crate::{HttpProxyResponse, SharedHandler};
 
+// Time allowed for service to reply close frame sent by client, before the connection is dropped
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+// Max size of close reason, which must fit in a control frame along with close code
+const MAX_CLOSE_REASON_SIZE: usize = 123;
+
+// Close frame relayed to the other side of session
+fn close_control(reason: Option<CloseReason>) -> WsControl {
+    match reason {
+        Some(reason) => WsControl::Close {
+            code: Some(reason.code.into()),
+            reason: reason.description,
+        },
+        None => WsControl::Close {
+            code: None,
+            reason: None,
+        },
+    }
+}
+
+// Close frame sent to this side of session
+fn close_reason(code: Option<u16>, reason: Option<String>) -> Option<CloseReason> {
+    code.map(|code| CloseReason {
+        code: CloseCode::from(code),
+        description: reason.map(|mut reason| {
+            let mut size = MAX_CLOSE_REASON_SIZE.min(reason.len());
+            while !reason.is_char_boundary(size) {
+                size -= 1;
+            }
+            reason.truncate(size);
+            reason
+        }),
+    })
+}
+
+/// Fragments of a websocket message received so far, the message is relayed once complete.
+#[derive(Default)]
+pub(crate) struct Fragments {
+    // Whether the message is binary, and data received
+    message: Option<(bool, Vec<u8>)>,
+}
+
+impl Fragments {
+    /// Add a fragment, the whole message is returned along with whether it is binary
+    /// once the last fragment is added.
+    pub(crate) fn push(&mut self, item: Item) -> Result<Option<(bool, Vec<u8>)>, CloseReason> {
+        let (is_last, data) = match item {
+            Item::FirstText(_) | Item::FirstBinary(_) if self.message.is_some() => {
+                return Err(protocol_error(
+                    "Fragmented message started before previous one ends",
+                ));
+            }
+            Item::FirstText(data) => {
+                self.message = Some((false, Vec::new()));
+                (false, data)
+            }
+            Item::FirstBinary(data) => {
+                self.message = Some((true, Vec::new()));
+                (false, data)
+            }
+            Item::Continue(data) => (false, data),
+            Item::Last(data) => (true, data),
+        };
+        let (is_binary, message) = self
+            .message
+            .as_mut()
+            .ok_or_else(|| protocol_error("Continuation frame without a message started"))?;
+        if message.len() + data.len() > MAX_WS_MESSAGE_SIZE {
+            self.message = None;
+            return Err(CloseReason {
+                code: CloseCode::Size,
+                description: Some(String::from("Message is too large")),
+            });
+        }
+        message.extend_from_slice(&data);
+        if !is_last {
+            return Ok(None);
+        }
+        if !*is_binary && std::str::from_utf8(message).is_err() {
+            self.message = None;
+            return Err(CloseReason {
+                code: CloseCode::Invalid,
+                description: Some(String::from("Text message is not valid utf-8")),
+            });
+        }
+        Ok(self.message.take())
+    }
+}
+
+fn protocol_error(description: &str) -> CloseReason {
+    CloseReason {
+        code: CloseCode::Protocol,
+        description: Some(description.to_string()),
+    }
+}
+
+// Queue frame to be relayed to peer, the actor waits while the queue is full,
+// so no more frames are read from its side until the peer catches up
+fn queue_frame<A>(
+    frame_sender: &mut mpsc::Sender<ProxyData>,
+    frame: ProxyData,
+    ctx: &mut A::Context,
+) where
+    A: Actor,
+    A::Context: AsyncContext<A>,
+{
+    if let Err(e) = frame_sender.try_send(frame) {
+        if e.is_full() {
+            let mut frame_sender = frame_sender.clone();
+            let frame = e.into_inner();
+            ctx.wait(fut::wrap_future(async move {
+                let _ = frame_sender.send(frame).await;
+            }));
+        }
+    }
+}
+
+// Close is queued even while the actor is stopping
+fn queue_close(frame_sender: &mpsc::Sender<ProxyData>, close: ProxyData) {
+    let mut frame_sender = frame_sender.clone();
+    Arbiter::spawn(async move {
+        let _ = frame_sender.send(close).await;
+    });
+}
+
 // Service side actor, connect to ws service and proxy data between libp2p stream and service
 pub(crate) struct ServiceSideWsActor {
     pub(crate) writer: SinkWrite<Message, SplitSink<Framed<BoxedSocket, Codec>, Message>>,
+    // Provider serving this session, released while the actor is dropped
+    #[allow(dead_code)]
+    pub(crate) provider: ProviderLease,
     pub(crate) client_peer_id: PeerId,
     pub(crate) request_id: String,
     pub(crate) p2p_handler: Data<SharedHandler>,
-    pub(crate) data_sender: mpsc::Sender<ProxyData>,
-    pub(crate) command_sender: mpsc::Sender<Command>,
+    // Frames relayed to client side gateway in order
+    pub(crate) frame_sender: mpsc::Sender<ProxyData>,
+    // Told with client peer and request id once the session is over, so it is forgotten
+    pub(crate) done_sender: Option<mpsc::Sender<(PeerId, String)>>,
+    pub(crate) fragments: Fragments,
+    // Close is passed to client, or received from it
+    pub(crate) closed: bool,
 }
 
 impl Actor for ServiceSideWsActor {
     type Context = Context<Self>;
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        // Connection to service is gone without closing handshake
+        if !self.closed {
+            self.send_close(ProxyData::close(
+                self.request_id.clone(),
+                1001,
+                String::from("Service connection closed"),
+            ));
+        }
+        self.done();
+    }
+}
+
+impl ServiceSideWsActor {
+    fn send_close(&mut self, close: ProxyData) {
+        self.closed = true;
+        queue_close(&self.frame_sender, close);
+        self.done();
+    }
+
+    // Sender has a slot of its own, which always takes the only message sent with it
+    fn done(&mut self) {
+        if let Some(mut done_sender) = self.done_sender.take() {
+            let _ = done_sender.try_send((self.client_peer_id, self.request_id.clone()));
+        }
+    }
 }
 
 // Handler for receiving message from service side
@@ -42,42 +203,108 @@ impl StreamHandler<Result<Frame, WsProtocolError>> for ServiceSideWsActor {
     fn handle(&mut self, msg: Result<Frame, WsProtocolError>, ctx: &mut Self::Context) {
         info!("Received service side message: {:?}", msg);
 
-        let proxy_data = match msg {
-            Ok(Frame::Text(text_msg)) => ProxyData {
-                request_id: self.request_id.clone(),
-                is_binary: false,
-                data: text_msg.to_vec(),
-            },
-            Ok(Frame::Binary(bin_msg)) => ProxyData {
-                request_id: self.request_id.clone(),
-                is_binary: true,
-                data: bin_msg.to_vec(),
+        let (is_binary, data, control) = match msg {
+            Ok(Frame::Text(text_msg)) => (false, text_msg.to_vec(), None),
+            Ok(Frame::Binary(bin_msg)) => (true, bin_msg.to_vec(), None),
+            Ok(Frame::Ping(payload)) => (false, payload.to_vec(), Some(WsControl::Ping)),
+            Ok(Frame::Pong(payload)) => (false, payload.to_vec(), Some(WsControl::Pong)),
+            Ok(Frame::Close(reason)) => {
+                // Reply of close sent by client needs no further handling,
+                // connection is dropped by service afterwards
+                if !self.closed {
+                    self.writer.write(Message::Close(reason.clone()));
+                    self.send_close(ProxyData {
+                        request_id: self.request_id.clone(),
+                        is_binary: false,
+                        data: Vec::new(),
+                        control: Some(close_control(reason)),
+                    });
+                    ctx.run_later(CLOSE_TIMEOUT, |_, ctx| ctx.stop());
+                }
+                return;
+            }
+            Ok(Frame::Continuation(item)) => match self.fragments.push(item) {
+                Ok(Some((is_binary, data))) => (is_binary, data, None),
+                Ok(None) => return,
+                Err(reason) => {
+                    // Both sides are closed, as the message can't be relayed
+                    warn!(
+                        "ServiceSideGateway: Ws message of {} dropped: {:?}",
+                        self.request_id, reason
+                    );
+                    if !self.closed {
+                        self.writer.write(Message::Close(Some(reason.clone())));
+                        self.send_close(ProxyData {
+                            request_id: self.request_id.clone(),
+                            is_binary: false,
+                            data: Vec::new(),
+                            control: Some(close_control(Some(reason))),
+                        });
+                        ctx.run_later(CLOSE_TIMEOUT, |_, ctx| ctx.stop());
+                    }
+                    return;
+                }
             },
-            _ => {
+            Err(e) => {
+                warn!(
+                    "ServiceSideGateway: Ws connection of {} failed: {}",
+                    self.request_id, e
+                );
+                self.send_close(ProxyData::close(
+                    self.request_id.clone(),
+                    1011,
+                    format!("Service connection failed: {}", e),
+                ));
+                ctx.stop();
                 return;
             }
         };
+        let proxy_data = ProxyData {
+            request_id: self.request_id.clone(),
+            is_binary,
+            data,
+            control,
+        };
 
         info!(
             "ServiceSideGateway: Prepare to send data to client {:?}, data: {:?}",
             self.client_peer_id, proxy_data
         );
 
-        // Send data to main loop
-        self.data_sender.try_send(proxy_data);
+        queue_frame::<Self>(&mut self.frame_sender, proxy_data, ctx);
     }
 }
 
 impl Handler<ProxyData> for ServiceSideWsActor {
     type Result = ();
 
-    fn handle(&mut self, msg: ProxyData, _ctx: &mut Context<Self>) {
+    fn handle(&mut self, msg: ProxyData, ctx: &mut Context<Self>) {
         info!("Message sent to service side: {:?}", msg);
-        if msg.is_binary {
-            self.writer.write(Message::Binary(Bytes::from(msg.data)));
-        } else {
-            self.writer
-                .write(Message::Text(String::from_utf8(msg.data).unwrap()));
+        match msg.control {
+            None if msg.is_binary => {
+                self.writer.write(Message::Binary(Bytes::from(msg.data)));
+            }
+            // Text is validated by client side gateway already
+            None => {
+                self.writer.write(Message::Text(
+                    String::from_utf8_lossy(&msg.data).into_owned(),
+                ));
+            }
+            Some(WsControl::Ping) => {
+                self.writer.write(Message::Ping(Bytes::from(msg.data)));
+            }
+            Some(WsControl::Pong) => {
+                self.writer.write(Message::Pong(Bytes::from(msg.data)));
+            }
+            Some(WsControl::Close { code, reason }) => {
+                if !self.closed {
+                    self.closed = true;
+                    self.writer
+                        .write(Message::Close(close_reason(code, reason)));
+                    self.done();
+                    ctx.run_later(CLOSE_TIMEOUT, |_, ctx| ctx.stop());
+                }
+            }
         }
     }
 }
@@ -87,9 +314,22 @@ impl actix::io::WriteHandler<WsProtocolError> for ServiceSideWsActor {}
 // Client side actor, receive message from client side and pass to service side gw with libp2p stream
 pub(crate) struct ClientSideWsActor {
     pub(crate) req_info: ProxyRequestInfo,
-    pub(crate) service_peer_id: PeerId,
+    // None if the service is served by this gateway
+    pub(crate) service_peer_id: Option<PeerId>,
     pub(crate) p2p_handler: Data<SharedHandler>,
     pub(crate) request_id_client_session_mapping: AppState<Sender<HttpProxyResponse>>,
+    // Frames relayed to service side gateway in order
+    pub(crate) frame_sender: mpsc::Sender<ProxyData>,
+    pub(crate) fragments: Fragments,
+    // Close is passed to service side gateway, which closes the session
+    pub(crate) closed: bool,
+}
+
+impl ClientSideWsActor {
+    fn send_close(&mut self, close: ProxyData) {
+        self.closed = true;
+        queue_close(&self.frame_sender, close);
+    }
 }
 
 impl Actor for ClientSideWsActor {
@@ -97,7 +337,6 @@ impl Actor for ClientSideWsActor {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         info!("ClientSideGateway: Started to receive message...");
-        let mut command_sender = self.p2p_handler.command_sender.lock().unwrap();
         let usage_args = ServiceUsageData {
             service_uuid: self.req_info.clone().service_id,
             nonce: "0".to_string(),
@@ -117,53 +356,109 @@ impl Actor for ClientSideWsActor {
             cost: "1".to_string(),
         };
 
-        block_on(command_sender.send(Command::SubmitUsage {
-            args: usage_args.clone().to_contract_args(),
-        }))
-        .unwrap();
-
-        // Block until connect request sent to ServiceSideGateway
-        block_on(command_sender.send(Command::SendRequest {
-            peer: self.service_peer_id,
-            request_id: self.req_info.clone().request_id,
-            data: bincode::serialize(&self.req_info).unwrap(),
-        }))
-        .unwrap();
+        let command = Command::SubmitUsage {
+            args: usage_args.to_contract_args(),
+        };
+        let mut command_sender = self.p2p_handler.command_sender.lock().unwrap().clone();
+        Arbiter::spawn(async move {
+            if let Err(e) = command_sender.send(command).await {
+                warn!("ClientSideGateway: Submit usage failed: {:?}", e);
+            }
+        });
     }
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
         Running::Stop
     }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        // Session is gone, responses to it are dropped and service side closes its connection,
+        // unless it is told with close frame already
+        let request_id = self.req_info.request_id.clone();
+        info!("ClientSideGateway: Session {} stopped", request_id);
+        delete(
+            self.request_id_client_session_mapping.clone(),
+            request_id.clone(),
+        );
+        if self.closed {
+            return;
+        }
+        // Local service session is closed once frames of this session end
+        if let Some(peer) = self.service_peer_id {
+            let mut command_sender = self.p2p_handler.command_sender.lock().unwrap().clone();
+            Arbiter::spawn(async move {
+                let _ = command_sender
+                    .send(Command::SendClose { peer, request_id })
+                    .await;
+            });
+        }
+    }
 }
 
 // Handler for message sent from client side
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ClientSideWsActor {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         info!("ClientSideGateway: receive ws msg from client: {:?}", msg);
-        let proxy_data = match msg.unwrap() {
-            ws::Message::Text(text_msg) => ProxyData {
-                request_id: self.req_info.request_id.to_string(),
-                is_binary: false,
-                data: text_msg.into_bytes().to_vec(),
-            },
-            ws::Message::Binary(binary_msg) => ProxyData {
-                request_id: self.req_info.request_id.to_string(),
-                is_binary: true,
-                data: binary_msg.to_vec(),
+        let request_id = self.req_info.request_id.to_string();
+        let (is_binary, data, control) = match msg {
+            Ok(ws::Message::Text(text_msg)) => (false, text_msg.into_bytes(), None),
+            Ok(ws::Message::Binary(binary_msg)) => (true, binary_msg.to_vec(), None),
+            Ok(ws::Message::Ping(payload)) => (false, payload.to_vec(), Some(WsControl::Ping)),
+            Ok(ws::Message::Pong(payload)) => (false, payload.to_vec(), Some(WsControl::Pong)),
+            Ok(ws::Message::Close(reason)) => {
+                // Complete closing handshake with client, and close service connection with the same reason
+                ctx.close(reason.clone());
+                self.send_close(ProxyData {
+                    request_id,
+                    is_binary: false,
+                    data: Vec::new(),
+                    control: Some(close_control(reason)),
+                });
+                ctx.stop();
+                return;
+            }
+            Ok(ws::Message::Continuation(item)) => match self.fragments.push(item) {
+                Ok(Some((is_binary, data))) => (is_binary, data, None),
+                Ok(None) => return,
+                Err(reason) => {
+                    warn!(
+                        "ClientSideGateway: Ws message of {} dropped: {:?}",
+                        request_id, reason
+                    );
+                    ctx.close(Some(reason.clone()));
+                    self.send_close(ProxyData {
+                        request_id,
+                        is_binary: false,
+                        data: Vec::new(),
+                        control: Some(close_control(Some(reason))),
+                    });
+                    ctx.stop();
+                    return;
+                }
             },
-            _ => return,
+            Ok(_) => return,
+            Err(e) => {
+                warn!("ClientSideGateway: Ws session {} failed: {}", request_id, e);
+                self.send_close(ProxyData::close(
+                    request_id,
+                    1002,
+                    format!("Client connection failed: {}", e),
+                ));
+                ctx.stop();
+                return;
+            }
+        };
+        let proxy_data = ProxyData {
+            request_id,
+            is_binary,
+            data,
+            control,
         };
-        let mut command_sender = self.p2p_handler.command_sender.lock().unwrap();
-        block_on(command_sender.send(Command::SendProxyData {
-            peer: self.service_peer_id,
-            data: bincode::serialize(&proxy_data).unwrap(),
-        }))
-        .unwrap();
-
         info!(
-            "ClientSideGateway: Sent data to service {:?}, data: {:?}",
+            "ClientSideGateway: Send data to service {:?}, data: {:?}",
             self.service_peer_id, proxy_data
         );
+        queue_frame::<Self>(&mut self.frame_sender, proxy_data, ctx);
     }
 }
 
@@ -171,6 +466,86 @@ impl Handler<ProxyData> for ClientSideWsActor {
     type Result = ();
 
     fn handle(&mut self, msg: ProxyData, ctx: &mut WebsocketContext<Self>) {
-        ctx.text(String::from_utf8(msg.data).unwrap());
+        match msg.control {
+            None if msg.is_binary => ctx.binary(msg.data),
+            None => match String::from_utf8(msg.data) {
+                Ok(text) => ctx.text(text),
+                Err(_) => {
+                    // Session ends, service side is closed while the actor stops
+                    ctx.close(Some(CloseReason {
+                        code: CloseCode::Invalid,
+                        description: Some(String::from("Text message is not valid utf-8")),
+                    }));
+                    ctx.stop();
+                }
+            },
+            Some(WsControl::Ping) => ctx.ping(&msg.data),
+            Some(WsControl::Pong) => ctx.pong(&msg.data),
+            Some(WsControl::Close { code, reason }) => {
+                ctx.close(close_reason(code, reason));
+                ctx.stop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_reason() {
+        let control = close_control(Some(CloseReason {
+            code: CloseCode::Away,
+            description: Some(String::from("bye")),
+        }));
+        assert_eq!(
+            control,
+            WsControl::Close {
+                code: Some(1001),
+                reason: Some(String::from("bye")),
+            }
+        );
+
+        // Reason is cut to fit in control frame
+        let reason = close_reason(Some(1011), Some("é".repeat(100))).unwrap();
+        assert_eq!(reason.code, CloseCode::Error);
+        assert_eq!(reason.description.unwrap().len(), 122);
+        assert_eq!(close_reason(None, Some(String::from("bye"))), None);
+    }
+
+    #[test]
+    fn test_fragments() {
+        let mut fragments = Fragments::default();
+        assert_eq!(
+            fragments.push(Item::FirstBinary(Bytes::from_static(b"ab"))),
+            Ok(None)
+        );
+        assert_eq!(
+            fragments.push(Item::Continue(Bytes::from_static(b"cd"))),
+            Ok(None)
+        );
+        assert_eq!(
+            fragments.push(Item::Last(Bytes::from_static(b"e"))),
+            Ok(Some((true, b"abcde".to_vec())))
+        );
+
+        assert_eq!(
+            fragments
+                .push(Item::Last(Bytes::from_static(b"e")))
+                .unwrap_err()
+                .code,
+            CloseCode::Protocol
+        );
+        fragments
+            .push(Item::FirstText(Bytes::from_static(b"\xff")))
+            .unwrap();
+        assert_eq!(
+            fragments
+                .push(Item::Last(Bytes::from_static(b"\xfe")))
+                .unwrap_err()
+                .code,
+            CloseCode::Invalid
+        );
     }
 }
@@ -1,7 +1,52 @@
 use std::collections::HashMap;
 
+use actix_web::ResponseError;
 use serde::{Deserialize, Serialize};
 
+use crate::gateway_error::GatewayError;
+
+/// Entries kept in the order received, where a key may appear several times,
+/// such as repeated http headers or query parameters. Header names are kept in lower case.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct MultiMap<V>(Vec<(String, V)>);
+
+impl<V> MultiMap<V> {
+    pub fn new() -> Self {
+        MultiMap(Vec::new())
+    }
+
+    /// Add entry after existing ones, even if the key exists already.
+    pub fn append(&mut self, key: String, value: V) {
+        self.0.push((key, value));
+    }
+
+    /// Replace all entries of the key with a single one.
+    pub fn set(&mut self, key: String, value: V) {
+        self.0.retain(|(k, _)| *k != key);
+        self.0.push((key, value));
+    }
+
+    /// First value of the key.
+    #[allow(dead_code)]
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &V)> {
+        self.0.iter().map(|(k, v)| (k, v))
+    }
+
+    pub fn retain<F: FnMut(&String, &V) -> bool>(&mut self, mut f: F) {
+        self.0.retain(|(k, v)| f(k, v));
+    }
+}
+
+impl<V> From<Vec<(String, V)>> for MultiMap<V> {
+    fn from(entries: Vec<(String, V)>) -> Self {
+        MultiMap(entries)
+    }
+}
+
 // TODO: Can some params be changed to Url
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ProxyRequestInfo {
@@ -11,9 +56,11 @@ pub struct ProxyRequestInfo {
     pub(crate) user_key: String,
     pub(crate) req_path: String,
     pub(crate) http_method: String,
-    pub(crate) headers: HashMap<String, String>,
-    pub(crate) query_args: HashMap<String, String>,
+    pub(crate) headers: MultiMap<Vec<u8>>,
+    pub(crate) query_args: MultiMap<String>,
     pub(crate) raw_body: Vec<u8>,
+    // Rest of body follows in BodyChunk messages
+    pub(crate) has_more_body: bool,
     pub(crate) json_data: HashMap<String, String>,
     pub(crate) form_data: HashMap<String, String>,
     pub(crate) is_websocket: bool,
@@ -25,6 +72,38 @@ pub struct ProxyData {
     pub(crate) request_id: String,
     pub(crate) is_binary: bool,
     pub(crate) data: Vec<u8>,
+    // Set if this is a control frame, whose payload is in data
+    pub(crate) control: Option<WsControl>,
+}
+
+impl ProxyData {
+    pub fn close(request_id: String, code: u16, reason: String) -> Self {
+        ProxyData {
+            request_id,
+            is_binary: false,
+            data: Vec::new(),
+            control: Some(WsControl::Close {
+                code: Some(code),
+                reason: Some(reason),
+            }),
+        }
+    }
+
+    pub fn is_close(&self) -> bool {
+        matches!(self.control, Some(WsControl::Close { .. }))
+    }
+}
+
+/// Websocket control frame relayed between client and service.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum WsControl {
+    Ping,
+    Pong,
+    // Code is absent if the closing side didn't send one
+    Close {
+        code: Option<u16>,
+        reason: Option<String>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -32,8 +111,42 @@ pub struct HttpProxyResponse {
     pub(crate) is_websocket_resp: bool,
     pub(crate) request_id: String,
     pub(crate) status_code: u16,
-    pub(crate) headers: HashMap<String, Vec<u8>>,
+    pub(crate) headers: MultiMap<Vec<u8>>,
     pub(crate) body: Vec<u8>,
+    // Rest of body follows in BodyChunk messages
+    pub(crate) has_more_body: bool,
+    // Websocket message is binary rather than text
+    pub(crate) is_binary: bool,
+    // Control frame of websocket session, body is its payload
+    pub(crate) ws_control: Option<WsControl>,
+}
+
+/// Piece of http body too large to be sent in one message, chunks of one body
+/// are sent one after another, the next one is sent after previous one acknowledged.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BodyChunk {
+    pub(crate) request_id: String,
+    pub(crate) seq: u64,
+    pub(crate) data: Vec<u8>,
+    pub(crate) is_last: bool,
+}
+
+impl HttpProxyResponse {
+    // Response generated by gateway while the request can't be served by provider
+    pub fn with_error(request_id: String, error: &GatewayError) -> Self {
+        let mut headers = MultiMap::new();
+        headers.append(String::from("content-type"), b"application/json".to_vec());
+        HttpProxyResponse {
+            is_websocket_resp: false,
+            request_id,
+            status_code: error.status_code().as_u16(),
+            headers,
+            body: error.to_json(),
+            has_more_body: false,
+            is_binary: false,
+            ws_control: None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -1,31 +1,60 @@
-use std::collections::HashMap;
-use std::convert::TryFrom;
-use std::error::Error;
-
 use actix::io::SinkWrite;
 use actix::{Actor, Addr, StreamHandler};
-use actix_web::web::service;
-use actix_web::{web, HttpRequest};
-use awc::http::{HeaderName, Uri};
-use awc::Client;
+use actix_web::error::{ErrorBadGateway, PayloadError};
+use actix_web::web::{Bytes, Data};
+use actix_web::{web, HttpRequest, ResponseError};
+use async_std::future::timeout;
+use awc::error::{ConnectError, SendRequestError, WsClientError};
+use awc::http::header::{CONTENT_LENGTH, HOST};
+use awc::http::Method;
+use awc::{Client, Connector};
 use futures::channel::mpsc;
+use futures::stream::LocalBoxStream;
+use futures::{future, stream, TryStreamExt};
 use log::{info, warn};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use reqwest::header::HeaderMap;
+use url::Url;
 
-use crate::forward_service_actors::ServiceSideWsActor;
-use crate::forward_service_models::ProxyRequestInfo;
-use crate::network::Command;
+use crate::circuit_breaker::{is_upstream_failure, CircuitBreakers};
+use crate::forward_service_actors::{Fragments, ServiceSideWsActor};
+use crate::forward_service_models::{MultiMap, ProxyData, ProxyRequestInfo};
+use crate::gateway_error::GatewayError;
+use crate::load_balancer::{select_provider_except, ProviderLease, ProviderSelector};
+use crate::protocol::{BODY_CHUNK_SIZE, MAX_WS_MESSAGE_SIZE};
+use crate::service::{ApronService, ApronServiceProvider};
 use crate::stream::StreamExt;
-use crate::{HttpProxyResponse, PeerId, ProxyData, SharedHandler};
+use crate::timeouts::TimeoutConfig;
+use crate::ws_stream::WS_FRAME_QUEUE_SIZE;
+use crate::{HttpProxyResponse, PeerId, SharedHandler};
+
+// Content types whose body is produced gradually by provider
+const STREAMING_CONTENT_TYPES: [&str; 3] = [
+    "text/event-stream",
+    "application/x-ndjson",
+    "application/stream+json",
+];
+
+/// Whether client expects a streamed response, such as server-sent events.
+pub(crate) fn is_streaming_request(req_info: &ProxyRequestInfo) -> bool {
+    req_info
+        .headers
+        .iter()
+        .filter(|(key, _)| key.eq_ignore_ascii_case("accept"))
+        .any(|(_, value)| {
+            let value = String::from_utf8_lossy(value).to_ascii_lowercase();
+            STREAMING_CONTENT_TYPES
+                .iter()
+                .any(|content_type| value.contains(content_type))
+        })
+}
 
 pub(crate) fn parse_request(
-    query_args: web::Query<HashMap<String, String>>,
+    query_args: web::Query<Vec<(String, String)>>,
     raw_body: web::Bytes,
     req: &HttpRequest,
     is_websocket: bool,
-) -> ProxyRequestInfo {
+) -> Result<ProxyRequestInfo, GatewayError> {
     // Generate unique request_id to receive correct response
     let request_id: String = thread_rng()
         .sample_iter(&Alphanumeric)
@@ -33,24 +62,37 @@ pub(crate) fn parse_request(
         .map(char::from)
         .collect();
 
-    let combined_key: String = req.match_info().query("user_key").parse().unwrap();
-    if combined_key.len() < 10 {
-        // TODO: Return 400 to client
-        panic!("user_key field error, should be format of <service_key><user_key>")
+    let combined_key = req.match_info().query("user_key");
+    let (service_id, user_key) = match (combined_key.get(..10), combined_key.get(10..)) {
+        (Some(service_id), Some(user_key)) => (service_id.to_string(), user_key.to_string()),
+        _ => {
+            return Err(GatewayError::BadRequest(String::from(
+                "user_key field error, should be format of <service_key><user_key>",
+            )))
+        }
+    };
+    if user_key.is_empty() {
+        return Err(GatewayError::Unauthorized(String::from(
+            "User key is missing",
+        )));
     }
-    let service_id = String::from(&combined_key[..10]);
-    let user_key = String::from(&combined_key[10..]);
+    let ver = req
+        .match_info()
+        .query("ver")
+        .parse()
+        .map_err(|_| GatewayError::BadRequest(String::from("Invalid api version")))?;
 
     let mut req_info = ProxyRequestInfo {
         service_id,
         request_id,
-        ver: req.match_info().query("ver").parse().unwrap(),
+        ver,
         user_key,
-        req_path: req.match_info().query("req_path").parse().unwrap(),
+        req_path: req.match_info().query("req_path").to_string(),
         http_method: req.method().to_string().to_uppercase(),
-        headers: Default::default(),
-        query_args: query_args.to_owned(),
+        headers: MultiMap::new(),
+        query_args: MultiMap::from(query_args.into_inner()),
         raw_body: raw_body.to_vec(),
+        has_more_body: false,
         json_data: Default::default(),
         form_data: Default::default(),
         is_websocket,
@@ -59,17 +101,30 @@ pub(crate) fn parse_request(
     // TODO: user key should be split into service id and user id.
 
     // Update header
+    let connection = req
+        .headers()
+        .get("connection")
+        .and_then(|connection| connection.to_str().ok());
     for header in req.headers().into_iter() {
+        // Those only apply to the connection with client, or are set again for provider
+        if header.0 == HOST
+            || header.0 == CONTENT_LENGTH
+            || is_hop_by_hop_header(header.0.as_str(), connection)
+        {
+            continue;
+        }
         req_info
             .headers
-            .insert(header.0.to_string(), header.1.to_str().unwrap().to_string());
+            .append(header.0.to_string(), header.1.as_bytes().to_vec());
     }
+    add_forwarded_headers(&mut req_info.headers, req);
 
     // Parse json / form data
-    let content_type = match req.headers().get("content-type") {
-        Some(content_type) => content_type.to_str().unwrap(),
-        None => "",
-    };
+    let content_type = req
+        .headers()
+        .get("content-type")
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or("");
 
     match serde_json::from_slice(&raw_body) {
         Ok(parsed_body) => {
@@ -84,90 +139,614 @@ pub(crate) fn parse_request(
 
     info!("{:?}", req_info);
 
-    return req_info;
+    Ok(req_info)
+}
+
+// Tell provider about the client, appended to ones set by proxies in front of this gateway.
+// Repeated list headers are same as a single one with values joined by comma.
+fn add_forwarded_headers(headers: &mut MultiMap<Vec<u8>>, req: &HttpRequest) {
+    let connection_info = req.connection_info();
+    let proto = connection_info.scheme().to_string();
+    let host = connection_info.host().to_string();
+
+    let mut forwarded = Vec::new();
+    if let Some(client_addr) = req.peer_addr() {
+        let client_ip = client_addr.ip().to_string();
+        headers.append(
+            String::from("x-forwarded-for"),
+            client_ip.clone().into_bytes(),
+        );
+        // IPv6 address must be quoted in Forwarded header
+        if client_addr.is_ipv6() {
+            forwarded.push(format!("for=\"[{}]\"", client_ip));
+        } else {
+            forwarded.push(format!("for={}", client_ip));
+        }
+    }
+    forwarded.push(format!("proto={}", proto));
+    forwarded.push(format!("host=\"{}\"", host));
+    headers.append(String::from("forwarded"), forwarded.join(";").into_bytes());
+    headers.set(String::from("x-forwarded-proto"), proto.into_bytes());
+    headers.set(String::from("x-forwarded-host"), host.into_bytes());
+}
+
+// Headers only meaningful for a single connection, which must not be forwarded
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Whether the header is hop-by-hop, `connection` is the value of Connection header
+/// which may name extra hop-by-hop headers.
+pub(crate) fn is_hop_by_hop_header(name: &str, connection: Option<&str>) -> bool {
+    HOP_BY_HOP_HEADERS
+        .iter()
+        .any(|header| header.eq_ignore_ascii_case(name))
+        || connection.map_or(false, |connection| {
+            connection
+                .split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case(name))
+        })
+}
+
+/// Whether the header belongs to websocket handshake, which is made by each connection itself.
+pub(crate) fn is_ws_handshake_header(name: &str) -> bool {
+    name.to_ascii_lowercase().starts_with("sec-websocket-")
+}
+
+// Subprotocols requested in websocket handshake, most preferred first
+fn ws_protocols(headers: &MultiMap<Vec<u8>>) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|(key, _)| key.eq_ignore_ascii_case("sec-websocket-protocol"))
+        .flat_map(|(_, value)| {
+            String::from_utf8_lossy(value)
+                .split(',')
+                .map(|protocol| protocol.trim().to_string())
+                .filter(|protocol| !protocol.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+// Join provider url with the path requested by client.
+// Dot segments are rejected, since they would lead out of the base path of provider
+// once normalized by url parser or upstream server.
+fn upstream_url(provider: &ApronServiceProvider, req_path: &str) -> Result<String, GatewayError> {
+    let has_dot_segment = req_path.split(&['/', '\\'][..]).any(|segment| {
+        let segment = segment.to_ascii_lowercase().replace("%2e", ".");
+        segment == "." || segment == ".."
+    });
+    if has_dot_segment {
+        return Err(GatewayError::BadRequest(format!(
+            "Invalid request path: {}",
+            req_path
+        )));
+    }
+
+    let base_url = provider.url();
+    if req_path.is_empty() {
+        return Ok(base_url);
+    }
+    Ok(format!(
+        "{}/{}",
+        base_url.trim_end_matches('/'),
+        req_path.trim_start_matches('/')
+    ))
+}
+
+// Provider not responding in time is reported as timeout, other failures as bad gateway
+fn upstream_error(e: SendRequestError) -> GatewayError {
+    match e {
+        SendRequestError::Timeout => {
+            GatewayError::GatewayTimeout(String::from("Provider did not respond in time"))
+        }
+        SendRequestError::Connect(ConnectError::Timeout) => {
+            GatewayError::GatewayTimeout(String::from("Connect to provider timed out"))
+        }
+        e => GatewayError::BadGateway(format!("Provider request failed: {}", e)),
+    }
 }
 
-pub fn send_http_request_blocking(
+/// Body streamed from upstream service.
+pub type UpstreamBody = LocalBoxStream<'static, Result<Bytes, PayloadError>>;
+
+/// Forward request to the provider, should only be invoked in service side gateway.
+/// Runs with awc client, so it must be spawned in actix runtime.
+/// `body` is rest of request body following `raw_body`, if it is sent in chunks.
+/// Small response body is returned in the response, larger one is returned as stream.
+pub async fn send_http_request(
     req_info: ProxyRequestInfo,
-    base_url: Option<String>,
-) -> Result<HttpProxyResponse, Box<dyn Error>> {
-    let service_url = match base_url {
-        Some(base) => base,
-        None => return Err("No API base passed.".into()),
-    };
+    body: Option<LocalBoxStream<'static, Result<Bytes, String>>>,
+    provider: &ApronServiceProvider,
+    timeouts: &TimeoutConfig,
+) -> Result<(HttpProxyResponse, Option<UpstreamBody>), GatewayError> {
+    let service_url = upstream_url(provider, &req_info.req_path)?;
+    let method = Method::from_bytes(req_info.http_method.as_bytes()).map_err(|_| {
+        GatewayError::BadRequest(format!("Invalid http method: {}", req_info.http_method))
+    })?;
 
-    let client = reqwest::blocking::Client::new();
+    let connector = Connector::new().timeout(timeouts.connect()).finish();
+    let mut client_req = Client::builder()
+        .connector(connector)
+        .finish()
+        .request(method, service_url)
+        .timeout(timeouts.upstream(is_streaming_request(&req_info)))
+        // Body is passed to client as is, with its content encoding
+        .no_decompress();
 
-    let client_req = match req_info.http_method.as_str() {
-        "GET" => client.get(service_url),
-        "POST" => client.post(service_url),
-        "PUT" => client.put(service_url),
-        "DELETE" => client.delete(service_url),
-        _ => panic!("Unknown http method: {}", req_info.http_method),
-    };
+    for (key, val) in req_info.headers.iter() {
+        // Those are set by client according to provider address and body sent,
+        // hop-by-hop ones may still come from older client side gateways
+        if key.eq_ignore_ascii_case("host")
+            || key.eq_ignore_ascii_case("content-length")
+            || is_hop_by_hop_header(key, None)
+        {
+            continue;
+        }
+        client_req = client_req.header(key.as_str(), val.clone());
+    }
+
+    // Fill query args
+    let query_args: Vec<(&String, &String)> = req_info.query_args.iter().collect();
 
-    let headers = {
-        let mut headers = HeaderMap::new();
-        for (key, val) in req_info.headers.iter() {
-            headers.insert(HeaderName::try_from(key).unwrap(), val.parse().unwrap());
+    let client_req = client_req
+        .query(&query_args)
+        .map_err(|e| GatewayError::BadRequest(format!("Invalid query args: {}", e)))?;
+    // Requests without body are sent without content length, as client did
+    let sending = match body {
+        Some(body) => {
+            let head = stream::once(future::ready(Ok(Bytes::from(req_info.raw_body))));
+            client_req.send_stream(head.chain(body).map_err(ErrorBadGateway))
         }
-        headers
+        None if req_info.raw_body.is_empty() => client_req.send(),
+        None => client_req.send_body(req_info.raw_body),
     };
+    let mut resp = sending.await.map_err(upstream_error)?;
 
-    // Fill query args
-    let mut query_args = Vec::new();
-    for (key, val) in req_info.query_args.iter() {
-        query_args.push((key, val));
+    let status_code = resp.status().as_u16();
+    let mut headers = MultiMap::new();
+    for (key, value) in resp.headers().iter() {
+        headers.append(key.to_string(), Vec::from(value.as_bytes()));
     }
 
-    let resp = client_req
-        .query(&query_args)
-        .headers(headers)
-        .send()
-        .unwrap();
-
-    Ok(HttpProxyResponse {
-        is_websocket_resp: false,
-        request_id: req_info.request_id,
-        status_code: resp.status().as_u16(),
-        headers: {
-            let mut headers = HashMap::new();
-            for (key, value) in resp.headers().iter() {
-                headers.insert(key.to_string(), Vec::from(value.to_str().unwrap()));
-            }
-            headers
+    // Body is returned within the response only if it is known to fit in one message
+    let content_length = resp
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    let (body, rest) = match content_length {
+        Some(length) if length <= BODY_CHUNK_SIZE => {
+            let body = resp.body().limit(BODY_CHUNK_SIZE).await.map_err(|e| {
+                GatewayError::BadGateway(format!("Read provider body failed: {}", e))
+            })?;
+            (body.to_vec(), None)
+        }
+        _ => (Vec::new(), Some(resp.boxed_local())),
+    };
+
+    Ok((
+        HttpProxyResponse {
+            is_websocket_resp: false,
+            request_id: req_info.request_id,
+            status_code,
+            headers,
+            body,
+            has_more_body: rest.is_some(),
+            is_binary: false,
+            ws_control: None,
         },
-        body: Vec::from(resp.text().unwrap()),
-    })
+        rest,
+    ))
 }
 
-// Function connects to websocket service, should only be invoked in service side gateway.
+/// Forward request to providers of the service, the request is sent again to another provider
+/// if it failed and the retry policy of the service allows.
+/// Returns provider of the last attempt, which should be kept until the response body is sent.
+pub async fn forward_to_providers(
+    req_info: ProxyRequestInfo,
+    mut body: Option<LocalBoxStream<'static, Result<Bytes, String>>>,
+    service: &ApronService,
+    mut provider: ProviderLease,
+    selector: Data<ProviderSelector>,
+    breakers: Data<CircuitBreakers>,
+) -> (
+    ProviderLease,
+    Result<(HttpProxyResponse, Option<UpstreamBody>), GatewayError>,
+) {
+    let timeouts = service.timeouts.clone().unwrap_or_default();
+    let retry = service.retry.clone().unwrap_or_default();
+    let header_rules = service.header_rules.clone().unwrap_or_default();
+    let mut req_info = req_info;
+    header_rules.apply_to_request(&mut req_info.headers);
+    let mut tried = Vec::new();
+    let mut attempt = 1;
+    loop {
+        // Body sent in chunks can only be sent once, retry policy won't allow another attempt then
+        let result = if breakers.providers.allow(&provider.key) {
+            let result =
+                send_http_request(req_info.clone(), body.take(), &provider.provider, &timeouts)
+                    .await;
+            let failure = match &result {
+                Ok((resp, _)) if is_upstream_failure(resp.status_code) => {
+                    Err(format!("Provider responded with {}", resp.status_code))
+                }
+                Ok(_) => Ok(()),
+                Err(e) if is_upstream_failure(e.status_code().as_u16()) => Err(e.to_string()),
+                Err(_) => Ok(()),
+            };
+            breakers.providers.record(&provider.key, failure);
+            result
+        } else {
+            Err(GatewayError::ServiceUnavailable(format!(
+                "Provider {} is failing, try again later",
+                provider.key
+            )))
+        };
+        let status_code = match &result {
+            Ok((resp, _)) => resp.status_code,
+            Err(e) => e.status_code().as_u16(),
+        };
+        if !retry.should_retry(&req_info, attempt, status_code) {
+            let result = result.map(|(mut resp, body)| {
+                header_rules.apply_to_response(&mut resp.headers);
+                (resp, body)
+            });
+            return (provider, result);
+        }
+
+        warn!(
+            "Attempt {} of {} to provider {} failed with {}, retrying",
+            attempt, req_info.request_id, provider.key, status_code
+        );
+        // Fail over to a provider not tried yet, the same one is tried again if there is no other
+        tried.push(provider.key.clone());
+        if let Some(next) = select_provider_except(
+            selector.clone(),
+            &breakers.providers,
+            service,
+            "http",
+            &tried,
+        ) {
+            provider = next;
+        }
+        async_std::task::sleep(retry.backoff(attempt)).await;
+        attempt += 1;
+    }
+}
+
+/// Session connected to websocket service, with frames of service to be relayed to client side gateway.
+pub(crate) type WsServiceSession = (
+    Addr<ServiceSideWsActor>,
+    mpsc::Receiver<ProxyData>,
+    HttpProxyResponse,
+);
+
+/// Connect to websocket service with handshake of client, should only be invoked in service side gateway.
+/// Handshake response of provider is returned either way, the session is started only if it is accepted.
+/// `done_sender` is told with client peer and request id once the session is over.
 pub(super) async fn connect_to_ws_service(
-    service_uri: &str,
+    provider: ProviderLease,
+    service: &ApronService,
     remote_peer_id: PeerId,
-    request_id: String,
+    mut req_info: ProxyRequestInfo,
     p2p_handler: web::Data<SharedHandler>,
-    data_sender: mpsc::Sender<ProxyData>,
-    command_sender: mpsc::Sender<Command>,
-) -> Addr<ServiceSideWsActor> {
-    let (resp, framed) = Client::new()
-        .ws(service_uri.parse::<Uri>().unwrap())
-        .connect()
-        .await
-        .unwrap();
+    done_sender: Option<mpsc::Sender<(PeerId, String)>>,
+) -> Result<WsServiceSession, HttpProxyResponse> {
+    let request_id = req_info.request_id.clone();
+    let timeouts = service.timeouts.clone().unwrap_or_default();
+    let header_rules = service.header_rules.clone().unwrap_or_default();
+    header_rules.apply_to_request(&mut req_info.headers);
+
+    let service_url = upstream_url(&provider.provider, &req_info.req_path)
+        .map_err(|e| HttpProxyResponse::with_error(request_id.clone(), &e))?;
+    let mut url = Url::parse(&service_url).map_err(|e| {
+        HttpProxyResponse::with_error(
+            request_id.clone(),
+            &GatewayError::BadGateway(format!("Invalid provider url: {}", e)),
+        )
+    })?;
+    let query_args: Vec<(&String, &String)> = req_info.query_args.iter().collect();
+    if !query_args.is_empty() {
+        url.query_pairs_mut().extend_pairs(query_args);
+    }
+
+    let mut client_req = Client::new()
+        .ws(url.as_str())
+        .max_frame_size(MAX_WS_MESSAGE_SIZE);
+    for (key, val) in req_info.headers.iter() {
+        // Handshake headers are made by the connection, except requested subprotocols set below
+        if key.eq_ignore_ascii_case("host")
+            || is_hop_by_hop_header(key, None)
+            || is_ws_handshake_header(key)
+        {
+            continue;
+        }
+        client_req = client_req.header(key.as_str(), val.clone());
+    }
+    let protocols = ws_protocols(&req_info.headers);
+    if !protocols.is_empty() {
+        client_req = client_req.protocols(protocols);
+    }
+
+    let (resp, framed) = match timeout(timeouts.connect(), client_req.connect()).await {
+        Ok(Ok(connected)) => connected,
+        // Refusal of provider is passed to client with its status
+        Ok(Err(WsClientError::InvalidResponseStatus(status))) => {
+            return Err(ws_handshake_response(
+                request_id,
+                status.as_u16(),
+                MultiMap::new(),
+            ));
+        }
+        Ok(Err(e)) => {
+            return Err(HttpProxyResponse::with_error(
+                request_id,
+                &GatewayError::BadGateway(format!("Connect to provider failed: {}", e)),
+            ));
+        }
+        Err(_) => {
+            return Err(HttpProxyResponse::with_error(
+                request_id,
+                &GatewayError::GatewayTimeout(String::from("Connect to provider timed out")),
+            ));
+        }
+    };
 
     info!("ServiceSideGateway: Resp: {:?}", resp);
+    let mut headers = MultiMap::new();
+    for (key, value) in resp.headers().iter() {
+        headers.append(key.to_string(), Vec::from(value.as_bytes()));
+    }
+    header_rules.apply_to_response(&mut headers);
+    let handshake = ws_handshake_response(request_id.clone(), resp.status().as_u16(), headers);
+
+    // Frames of service are queued until client side gateway opens stream of the session
+    let (frame_sender, frame_receiver) = mpsc::channel(WS_FRAME_QUEUE_SIZE);
 
     let (sink, stream) = framed.split();
-    ServiceSideWsActor::create(|ctx| {
+    let addr = ServiceSideWsActor::create(|ctx| {
         ServiceSideWsActor::add_stream(stream, ctx);
         ServiceSideWsActor {
             writer: SinkWrite::new(sink, ctx),
+            provider,
             client_peer_id: remote_peer_id,
             request_id,
             p2p_handler,
-            data_sender,
-            command_sender,
+            frame_sender,
+            done_sender,
+            fragments: Fragments::default(),
+            closed: false,
         }
-    })
+    });
+    Ok((addr, frame_receiver, handshake))
+}
+
+// Handshake response of provider passed back to client side gateway
+fn ws_handshake_response(
+    request_id: String,
+    status_code: u16,
+    headers: MultiMap<Vec<u8>>,
+) -> HttpProxyResponse {
+    HttpProxyResponse {
+        is_websocket_resp: true,
+        request_id,
+        status_code,
+        headers,
+        body: Vec::new(),
+        has_more_body: false,
+        is_binary: false,
+        ws_control: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::rt::time::delay_for;
+    use actix_web::rt::System;
+    use actix_web::test::{self, TestServer};
+    use actix_web::{App, HttpResponse};
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::load_balancer::select_provider;
+    use crate::retry::RetryPolicy;
+    use crate::test_fixtures::{new_provider, new_request, new_service};
+
+    fn server_provider(id: &str, server: &TestServer) -> ApronServiceProvider {
+        new_provider(id, &server.addr().to_string())
+    }
+
+    // Forward request to providers the way service side gateway does, returns key of the last provider tried
+    async fn forward(
+        service: &ApronService,
+        req_info: ProxyRequestInfo,
+        body: Option<LocalBoxStream<'static, Result<Bytes, String>>>,
+    ) -> (
+        String,
+        Result<(HttpProxyResponse, Option<UpstreamBody>), GatewayError>,
+    ) {
+        let selector = Data::new(ProviderSelector::default());
+        let breakers = Data::new(CircuitBreakers::default());
+        let provider = select_provider(selector.clone(), &breakers.providers, service, "http")
+            .expect("Provider to be selected.");
+        let (provider, result) =
+            forward_to_providers(req_info, body, service, provider, selector, breakers).await;
+        (provider.key.clone(), result)
+    }
+
+    #[test]
+    fn test_upstream_url() {
+        let provider = new_provider("p1", "localhost:8080/api/");
+        let url = |req_path: &str| upstream_url(&provider, req_path).ok();
+
+        assert_eq!(url(""), Some("http://localhost:8080/api/".to_string()));
+        assert_eq!(url("/"), Some("http://localhost:8080/api/".to_string()));
+        assert_eq!(
+            url("//v1//items"),
+            Some("http://localhost:8080/api/v1//items".to_string())
+        );
+        assert_eq!(
+            url("v1/a..b/.c"),
+            Some("http://localhost:8080/api/v1/a..b/.c".to_string())
+        );
+
+        for req_path in &[
+            "..",
+            "/../admin",
+            "v1/./items",
+            "v1/../../admin",
+            "v1/%2e%2E/admin",
+            "v1/.%2e/admin",
+            "v1\\..\\admin",
+        ] {
+            assert_eq!(url(req_path), None, "{} is accepted", req_path);
+        }
+    }
+
+    #[test]
+    fn test_fail_over_to_other_provider() {
+        System::new("test").block_on(async {
+            let failing = test::start(|| {
+                App::new().default_service(web::to(HttpResponse::ServiceUnavailable))
+            });
+            let working = test::start(|| {
+                App::new().default_service(web::to(|| HttpResponse::Ok().body("ok")))
+            });
+            let service = new_service(vec![
+                server_provider("failing", &failing),
+                server_provider("working", &working),
+            ]);
+
+            let (key, result) = forward(&service, new_request("GET"), None).await;
+            let (resp, _) = result.unwrap();
+            assert_eq!(key, "test_service/working");
+            assert_eq!(resp.status_code, 200);
+            assert_eq!(resp.body, b"ok".to_vec());
+
+            // Requests which are not idempotent are not sent again
+            let (key, result) = forward(&service, new_request("POST"), None).await;
+            assert_eq!(key, "test_service/failing");
+            assert_eq!(result.unwrap().0.status_code, 503);
+        });
+    }
+
+    #[test]
+    fn test_upstream_timeout() {
+        System::new("test").block_on(async {
+            async fn respond_slowly() -> HttpResponse {
+                delay_for(Duration::from_secs(3)).await;
+                HttpResponse::Ok().finish()
+            }
+            let slow = test::start(|| App::new().default_service(web::to(respond_slowly)));
+            let mut service = new_service(vec![server_provider("slow", &slow)]);
+            service.timeouts = Some(TimeoutConfig {
+                upstream_secs: Some(1),
+                ..Default::default()
+            });
+            service.retry = Some(RetryPolicy {
+                max_attempts: Some(1),
+                ..Default::default()
+            });
+
+            let (_, result) = forward(&service, new_request("GET"), None).await;
+            match result {
+                Err(e) => assert_eq!(e.status_code().as_u16(), 504),
+                Ok((resp, _)) => panic!("Unexpected response: {}", resp.status_code),
+            }
+        });
+    }
+
+    #[test]
+    fn test_keep_repeated_headers_and_query_args() {
+        System::new("test").block_on(async {
+            let echo = test::start(|| {
+                App::new().default_service(web::to(|req: HttpRequest| {
+                    let tags: Vec<&str> = req
+                        .headers()
+                        .get_all("x-tag")
+                        .map(|value| value.to_str().unwrap())
+                        .collect();
+                    HttpResponse::Ok()
+                        .header("set-cookie", "a=1")
+                        .header("set-cookie", "b=2")
+                        .body(format!("{}\n{}", req.query_string(), tags.join(",")))
+                }))
+            });
+            let provider = server_provider("echo", &echo);
+
+            let mut req_info = new_request("GET");
+            for (key, value) in &[("id", "1"), ("q", "a b"), ("id", "2")] {
+                req_info
+                    .query_args
+                    .append(key.to_string(), value.to_string());
+            }
+            req_info
+                .headers
+                .append("x-tag".to_string(), b"one".to_vec());
+            req_info
+                .headers
+                .append("x-tag".to_string(), b"two".to_vec());
+            let (resp, _) = send_http_request(req_info, None, &provider, &TimeoutConfig::default())
+                .await
+                .unwrap();
+
+            assert_eq!(
+                String::from_utf8(resp.body).unwrap(),
+                "id=1&q=a+b&id=2\none,two"
+            );
+            let cookies: Vec<&[u8]> = resp
+                .headers
+                .iter()
+                .filter(|(key, _)| key.as_str() == "set-cookie")
+                .map(|(_, value)| value.as_slice())
+                .collect();
+            assert_eq!(cookies, vec![&b"a=1"[..], &b"b=2"[..]]);
+        });
+    }
+
+    #[test]
+    fn test_stream_large_body() {
+        System::new("test").block_on(async {
+            let echo = test::start(|| {
+                App::new().default_service(web::to(|body: web::Payload| {
+                    HttpResponse::Ok().streaming(body)
+                }))
+            });
+            let service = new_service(vec![server_provider("echo", &echo)]);
+            let data: Vec<u8> = (0..3 * BODY_CHUNK_SIZE).map(|i| i as u8).collect();
+
+            // Head of body comes with the request, the rest follows in pieces
+            let mut req_info = new_request("POST");
+            req_info.raw_body = data[..1000].to_vec();
+            req_info.has_more_body = true;
+            let pieces: Vec<Result<Bytes, String>> = data[1000..]
+                .chunks(BODY_CHUNK_SIZE)
+                .map(|piece| Ok(Bytes::copy_from_slice(piece)))
+                .collect();
+            let (_, result) =
+                forward(&service, req_info, Some(stream::iter(pieces).boxed_local())).await;
+
+            // Response too large for one message is streamed as well
+            let (resp, rest) = result.unwrap();
+            assert_eq!(resp.status_code, 200);
+            assert!(resp.has_more_body && resp.body.is_empty());
+            let mut received = Vec::new();
+            let mut rest = rest.unwrap();
+            while let Some(piece) = rest.next().await {
+                received.extend_from_slice(&piece.unwrap());
+            }
+            assert_eq!(received, data);
+        });
+    }
 }
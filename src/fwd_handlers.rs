@@ -1,150 +1,526 @@
-use std::collections::HashMap;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use actix::Arbiter;
+use actix_web::dev::HttpResponseBuilder;
+use actix_web::error::{ErrorBadGateway, PayloadError};
+use actix_web::http::StatusCode;
 use actix_web::web::Data;
-use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use actix_web::{web, Error, HttpRequest, HttpResponse, ResponseError};
 use actix_web_actors::ws;
+use async_std::future::timeout;
 use futures::channel::mpsc;
 use futures::channel::mpsc::{Receiver, Sender};
-use futures::{SinkExt, StreamExt};
+use futures::channel::oneshot;
+use futures::stream::LocalBoxStream;
+use futures::{future, stream, FutureExt, SinkExt, StreamExt, TryStreamExt};
 use log::{debug, error, info, warn};
 
-use crate::forward_service_actors::ClientSideWsActor;
+use crate::circuit_breaker::{is_upstream_failure, CircuitBreakers};
+use crate::forward_service_actors::{ClientSideWsActor, Fragments};
 use crate::forward_service_models::{
     HttpProxyResponse, ProxyData, ProxyRequestInfo, ServiceUsageData,
 };
-use crate::network::Command;
-use crate::state::{get, set, AppState};
-use crate::{forward_service_utils::parse_request, PeerId, SharedHandler};
-use crate::{helpers, ApronService};
+use crate::forward_service_utils::{
+    connect_to_ws_service, forward_to_providers, is_hop_by_hop_header, is_streaming_request,
+    is_ws_handshake_header, parse_request,
+};
+use crate::gateway_error::GatewayError;
+use crate::load_balancer::{select_provider, ProviderSelector};
+use crate::network::{body_stream, send_body_chunks, Command};
+use crate::protocol::BODY_CHUNK_SIZE;
+use crate::service::get_active_service;
+use crate::state::{delete, set, AppState};
+use crate::ws_stream::{pipe_ws_session, relay_ws_session, WS_FRAME_QUEUE_SIZE};
+use crate::ApronService;
+use crate::{PeerId, SharedHandler};
 
-fn prepare_for_sending_p2p_transaction(
-    query_args: web::Query<HashMap<String, String>>,
+#[allow(clippy::too_many_arguments)]
+async fn prepare_for_sending_p2p_transaction(
+    service_data: AppState<ApronService>,
+    query_args: web::Query<Vec<(String, String)>>,
     raw_body: web::Bytes,
-    req: HttpRequest,
+    req: &HttpRequest,
     is_websocket: bool,
-) -> (ProxyRequestInfo, PeerId) {
+    p2p_handler: &Data<SharedHandler>,
+    local_peer_id: &PeerId,
+    breakers: &Data<CircuitBreakers>,
+) -> Result<(ProxyRequestInfo, ApronService, PeerId), GatewayError> {
     // Parse request from client side
-    let req_info = parse_request(query_args, raw_body, &req, is_websocket);
+    let req_info = parse_request(query_args, raw_body, req, is_websocket)?;
+
+    debug!("All services data in local: {:?}", service_data.clone());
+    let service = match get_active_service(service_data, req_info.clone().service_id) {
+        Some(service) => service,
+        None => {
+            error!("Service {:?} not found", req_info.service_id);
+            return Err(GatewayError::NotFound(format!(
+                "Service {} not found",
+                req_info.service_id
+            )));
+        }
+    };
 
-    // Generate peer id from seed
-    // TODO: Replace this hard coded value to value fetched from service registration DB
-    let (_, remote_peer_id) = helpers::generate_peer_id_from_seed(Some(1));
+    let remote_peer_id =
+        resolve_service_peer(&service, p2p_handler, local_peer_id, breakers).await?;
 
-    (req_info, remote_peer_id)
+    Ok((req_info, service, remote_peer_id))
+}
+
+// Locate the peer which registered the service, and make sure it is connected.
+// Services registered on this gateway are served by it directly, there is nothing to connect.
+async fn resolve_service_peer(
+    service: &ApronService,
+    p2p_handler: &Data<SharedHandler>,
+    local_peer_id: &PeerId,
+    breakers: &Data<CircuitBreakers>,
+) -> Result<PeerId, GatewayError> {
+    let remote_peer_id = match service
+        .peer_id
+        .as_ref()
+        .and_then(|peer_id| peer_id.parse::<PeerId>().ok())
+    {
+        Some(peer_id) => peer_id,
+        None => {
+            error!(
+                "Owning peer of service {:?} is unknown: {:?}",
+                service.id, service.peer_id
+            );
+            return Err(GatewayError::NotFound(format!(
+                "Owning peer of service {} is unknown",
+                service.id
+            )));
+        }
+    };
+
+    if remote_peer_id == *local_peer_id {
+        return Ok(remote_peer_id);
+    }
+
+    // Peer failing repeatedly is not bothered until its circuit lets a trial through
+    let peer_key = remote_peer_id.to_base58();
+    if !breakers.peers.allow(&peer_key) {
+        warn!("Circuit of service peer {:?} is open", remote_peer_id);
+        return Err(GatewayError::ServiceUnavailable(format!(
+            "Service gateway {} is failing, try again later",
+            peer_key
+        )));
+    }
+
+    let (sender, receiver) = oneshot::channel();
+    let mut command_sender = p2p_handler.command_sender.lock().unwrap().clone();
+    command_sender
+        .send(Command::EnsureConnected {
+            peer: remote_peer_id,
+            sender,
+        })
+        .await
+        .map_err(|_| network_stopped())?;
+
+    let connect_timeout = service.timeouts.clone().unwrap_or_default().connect();
+    match timeout(connect_timeout, receiver).await {
+        Ok(Ok(Ok(()))) => Ok(remote_peer_id),
+        Ok(Ok(Err(e))) => {
+            error!("Service peer {:?} unreachable: {}", remote_peer_id, e);
+            breakers.peers.record(&peer_key, Err(e.clone()));
+            Err(GatewayError::ServiceUnavailable(e))
+        }
+        Ok(Err(_)) => Err(network_stopped()),
+        Err(_) => {
+            error!("Connect to service peer {:?} timed out", remote_peer_id);
+            let e = String::from("Connect to service gateway timed out");
+            breakers.peers.record(&peer_key, Err(e.clone()));
+            Err(GatewayError::GatewayTimeout(e))
+        }
+    }
+}
+
+// Reported while the p2p event loop is gone, normally only during shutdown
+fn network_stopped() -> GatewayError {
+    GatewayError::ServiceUnavailable(String::from("P2p network is not running"))
+}
+
+// Usage of service by the request, submitted to stat contract
+fn submit_usage_command(service: &ApronService, req_info: &ProxyRequestInfo) -> Command {
+    let usage_args = ServiceUsageData {
+        service_uuid: service.clone().id,
+        nonce: "0".to_string(),
+        user_key: req_info.clone().user_key,
+        start_time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros()
+            .to_string(),
+        end_time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros()
+            .to_string(),
+        usage: "1".to_string(),
+        price_plan: "test_plan".to_string(),
+        cost: "1".to_string(),
+    };
+    Command::SubmitUsage {
+        args: usage_args.to_contract_args(),
+    }
+}
+
+// Tells service side gateway to stop the request if it is abandoned before finished,
+// such as client went away or websocket handshake failed
+struct CloseGuard {
+    command_sender: mpsc::Sender<Command>,
+    peer: PeerId,
+    request_id: String,
+    finished: bool,
+}
+
+impl Drop for CloseGuard {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        info!("ClientSideGateway: Request {} abandoned", self.request_id);
+        let mut command_sender = self.command_sender.clone();
+        let command = Command::SendClose {
+            peer: self.peer,
+            request_id: self.request_id.clone(),
+        };
+        Arbiter::spawn(async move {
+            let _ = command_sender.send(command).await;
+        });
+    }
+}
+
+// Read beginning of request body, returns true with it if more data follows
+async fn read_body_head(payload: &mut web::Payload) -> Result<(web::Bytes, bool), PayloadError> {
+    let mut head = web::BytesMut::new();
+    while head.len() < BODY_CHUNK_SIZE {
+        match payload.next().await {
+            Some(data) => head.extend_from_slice(&data?),
+            None => return Ok((head.freeze(), false)),
+        }
+    }
+    Ok((head.freeze(), true))
 }
 
+// Reproduce response of the provider, without headers only meaningful to the upstream connection.
+// `body` is rest of the body following the one in response, if it is sent in chunks,
+// which is relayed to client piece by piece as it arrives.
+// `guard` is set if the response comes from service side gateway.
+fn to_client_response(
+    resp: HttpProxyResponse,
+    body: Option<LocalBoxStream<'static, Result<web::Bytes, String>>>,
+    guard: Option<CloseGuard>,
+) -> HttpResponse {
+    let status = StatusCode::from_u16(resp.status_code).unwrap_or(StatusCode::BAD_GATEWAY);
+    let connection = resp
+        .headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("connection"))
+        .map(|(_, value)| String::from_utf8_lossy(value).to_string());
+
+    let mut builder = HttpResponse::build(status);
+    for (key, value) in resp.headers.iter() {
+        // Length is decided by body sent
+        if key.eq_ignore_ascii_case("content-length")
+            || is_hop_by_hop_header(key, connection.as_deref())
+        {
+            continue;
+        }
+        builder.header(key.as_str(), value.clone());
+    }
+    match body {
+        Some(body) => {
+            let head = stream::once(future::ready(Ok(web::Bytes::from(resp.body))));
+            // Guard lives with the body, it fires if client disconnects before body ended
+            let body = stream::unfold(
+                (head.chain(body), guard),
+                |(mut body, mut guard)| async move {
+                    match body.next().await {
+                        Some(data) => Some((data, (body, guard))),
+                        None => {
+                            if let Some(guard) = guard.as_mut() {
+                                guard.finished = true;
+                            }
+                            None
+                        }
+                    }
+                },
+            );
+            builder.streaming(Box::pin(body).map_err(ErrorBadGateway))
+        }
+        None => {
+            if let Some(mut guard) = guard {
+                guard.finished = true;
+            }
+            builder.body(resp.body)
+        }
+    }
+}
+
+// Serve request of service registered on this gateway, the same way service side gateway does for peers
+async fn forward_to_local_service(
+    req_info: ProxyRequestInfo,
+    payload: web::Payload,
+    service: ApronService,
+    selector: Data<ProviderSelector>,
+    breakers: Data<CircuitBreakers>,
+) -> Result<HttpResponse, GatewayError> {
+    let provider = select_provider(selector.clone(), &breakers.providers, &service, "http")
+        .ok_or_else(|| {
+            GatewayError::ServiceUnavailable(format!(
+                "No healthy http provider for service {}",
+                service.id
+            ))
+        })?;
+    let body = if req_info.has_more_body {
+        Some(payload.map_err(|e| e.to_string()).boxed_local())
+    } else {
+        None
+    };
+    let request_id = req_info.request_id.clone();
+    let total_timeout = service
+        .timeouts
+        .clone()
+        .unwrap_or_default()
+        .total(is_streaming_request(&req_info));
+    let forwarded = timeout(
+        total_timeout,
+        forward_to_providers(req_info, body, &service, provider, selector, breakers),
+    )
+    .await;
+    match forwarded {
+        Ok((provider, Ok((resp, download)))) => {
+            // Provider is released once the whole body is sent
+            let body = download.map(|download| {
+                download
+                    .map(move |data| {
+                        let _ = &provider;
+                        data.map_err(|e| e.to_string())
+                    })
+                    .boxed_local()
+            });
+            Ok(to_client_response(resp, body, None))
+        }
+        Ok((provider, Err(e))) => {
+            error!("Request to provider {} failed: {}", provider.key, e);
+            Err(e)
+        }
+        Err(_) => {
+            error!("Request {} timed out", request_id);
+            Err(GatewayError::GatewayTimeout(String::from(
+                "Request to provider timed out",
+            )))
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn forward_http_proxy_request(
     service_data: AppState<ApronService>,
-    query_args: web::Query<HashMap<String, String>>,
-    raw_body: web::Bytes,
+    query_args: web::Query<Vec<(String, String)>>,
+    mut payload: web::Payload,
     req: HttpRequest,
     p2p_handler: Data<SharedHandler>,
     local_peer_id: Data<PeerId>,
-    request_id_client_session_mapping: AppState<Sender<HttpProxyResponse>>,
-) -> impl Responder {
+    selector: Data<ProviderSelector>,
+    breakers: Data<CircuitBreakers>,
+) -> Result<HttpResponse, GatewayError> {
     debug!("ClientSideGateway: Receive HTTP request: {:?}", req);
 
-    let (req_info, remote_peer_id) =
-        prepare_for_sending_p2p_transaction(query_args, raw_body, req, false);
+    // Large body is sent to service side gateway in chunks after the request
+    let (raw_body, has_more_body) = read_body_head(&mut payload)
+        .await
+        .map_err(|e| GatewayError::BadRequest(format!("Read request body failed: {}", e)))?;
 
-    debug!("All services data in local: {:?}", service_data.clone());
-    let service = get(service_data, req_info.clone().service_id);
-    if service.is_none() {
-        error!("Service {:?} not found", req_info.clone().service_id);
-        HttpResponse::NotFound().body(format!(
-            "Service {:?} not found",
-            req_info.clone().service_id
-        ))
-    } else {
-        debug!("ClientSideGateway: Req info: {:?}", req_info);
-        debug!("ClientSideGateway: remote peer: {:?}", remote_peer_id);
-
-        let (resp_sender, mut resp_receiver): (
-            Sender<HttpProxyResponse>,
-            Receiver<HttpProxyResponse>,
-        ) = mpsc::channel(0);
-
-        // Save mapping between request_id and response sender,
-        // which will be used to locate correct client session while getting response
-        set(
-            request_id_client_session_mapping.clone(),
-            req_info.clone().request_id,
-            resp_sender.clone(),
-        );
+    let (mut req_info, service, remote_peer_id) = prepare_for_sending_p2p_transaction(
+        service_data,
+        query_args,
+        raw_body,
+        &req,
+        false,
+        &p2p_handler,
+        &local_peer_id,
+        &breakers,
+    )
+    .await?;
 
-        debug!(
-            "ClientSideGateway: Fwd req id mapping: {:?}",
-            request_id_client_session_mapping.as_ref()
-        );
+    req_info.has_more_body = has_more_body;
 
-        // Send ProxyRequestInfo to service side gateway via stream
-        let mut command_sender = p2p_handler.command_sender.lock().unwrap();
-        command_sender
-            .send(Command::SendRequest {
-                peer: remote_peer_id,
-                request_id: req_info.clone().request_id,
-                data: bincode::serialize(&req_info).unwrap(),
-            })
-            .await
-            .unwrap();
-
-        let usage_args = ServiceUsageData {
-            service_uuid: service.clone().unwrap().clone().id,
-            nonce: "0".to_string(),
-            user_key: req_info.clone().user_key,
-            start_time: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_micros()
-                .to_string(),
-            end_time: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_micros()
-                .to_string(),
-            usage: "1".to_string(),
-            price_plan: "test_plan".to_string(),
-            cost: "1".to_string(),
-        };
-        command_sender
-            .send(Command::SubmitUsage {
-                args: usage_args.clone().to_contract_args(),
-            })
-            .await
-            .unwrap();
+    debug!("ClientSideGateway: Req info: {:?}", req_info);
+    debug!("ClientSideGateway: remote peer: {:?}", remote_peer_id);
+
+    let mut command_sender = p2p_handler.command_sender.lock().unwrap().clone();
+    command_sender
+        .send(submit_usage_command(&service, &req_info))
+        .await
+        .map_err(|_| network_stopped())?;
+
+    if remote_peer_id == **local_peer_id {
+        return forward_to_local_service(req_info, payload, service, selector, breakers).await;
+    }
+
+    // Send ProxyRequestInfo to service side gateway, the response is replied on the same request
+    let (resp_sender, resp_receiver) = oneshot::channel();
+    command_sender
+        .send(Command::SendHttpRequest {
+            peer: remote_peer_id,
+            info: req_info.clone(),
+            sender: resp_sender,
+        })
+        .await
+        .map_err(|_| network_stopped())?;
+
+    // Dropped with this handler if client disconnects while waiting for response
+    let mut guard = CloseGuard {
+        command_sender: command_sender.clone(),
+        peer: remote_peer_id,
+        request_id: req_info.request_id.clone(),
+        finished: false,
+    };
+
+    // Rest of body is uploaded while waiting for the response, and stopped once response arrived
+    let request_id = req_info.request_id.clone();
+    let mut upload = Box::pin(async {
+        if has_more_body {
+            let uploaded = send_body_chunks(
+                command_sender.clone(),
+                remote_peer_id,
+                request_id.clone(),
+                payload,
+            )
+            .await;
+            if let Err(e) = uploaded {
+                warn!("Uploading body of {} stopped: {}", request_id, e);
+            }
+        }
+    })
+    .fuse();
+    let retry = service.retry.clone().unwrap_or_default();
+    let waiting = async {
+        let mut resp_receiver = resp_receiver.fuse();
+        let mut attempt = 1;
+        loop {
+            let result = loop {
+                futures::select! {
+                    result = resp_receiver => break result.unwrap_or_else(|_| Err(network_stopped())),
+                    _ = upload => {}
+                }
+            };
 
-        match resp_receiver.next().await {
-            Some(resp) => {
-                info!("Got HttpProxyResponse data");
-                HttpResponse::Ok().body(resp.body)
+            // Only failures of reaching service side gateway count, failures of providers
+            // are tracked by the service side gateway, which retries them itself
+            let peer_key = remote_peer_id.to_base58();
+            match &result {
+                Err(e) if is_upstream_failure(e.status_code().as_u16()) => {
+                    breakers.peers.record(&peer_key, Err(e.to_string()))
+                }
+                _ => breakers.peers.record(&peer_key, Ok(())),
             }
-            _ => {
-                error!("Got Non HttpProxyResponse data");
-                HttpResponse::ServiceUnavailable().body("")
+            let e = match result {
+                Err(e) if retry.should_retry(&req_info, attempt, e.status_code().as_u16()) => e,
+                result => return result,
+            };
+            warn!(
+                "Attempt {} of {} to {} failed: {}, retrying",
+                attempt, req_info.request_id, remote_peer_id, e
+            );
+            async_std::task::sleep(retry.backoff(attempt)).await;
+            if !breakers.peers.allow(&peer_key) {
+                return Err(GatewayError::ServiceUnavailable(format!(
+                    "Service gateway {} is failing, try again later",
+                    peer_key
+                )));
             }
+            let (resp_sender, receiver) = oneshot::channel();
+            command_sender
+                .clone()
+                .send(Command::SendHttpRequest {
+                    peer: remote_peer_id,
+                    info: req_info.clone(),
+                    sender: resp_sender,
+                })
+                .await
+                .map_err(|_| network_stopped())?;
+            resp_receiver = receiver.fuse();
+            attempt += 1;
+        }
+    };
+    let total_timeout = service
+        .timeouts
+        .clone()
+        .unwrap_or_default()
+        .total(is_streaming_request(&req_info));
+    let result = timeout(total_timeout, waiting).await;
+    drop(upload);
+
+    match result {
+        Ok(Ok((resp, body))) => {
+            info!("Got HttpProxyResponse data");
+            let body = body.map(|body| body_stream(body, command_sender));
+            Ok(to_client_response(resp, body, Some(guard)))
+        }
+        Ok(Err(e)) => {
+            guard.finished = true;
+            error!("Request {} failed: {}", req_info.request_id, e);
+            Err(e)
+        }
+        // Guard tells service side gateway to abort the upstream request
+        Err(_) => {
+            error!("Request {} timed out", req_info.request_id);
+            Err(GatewayError::GatewayTimeout(String::from(
+                "Service gateway did not respond in time",
+            )))
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn forward_ws_proxy_request(
     service_data: AppState<ApronService>,
-    query_args: web::Query<HashMap<String, String>>,
+    query_args: web::Query<Vec<(String, String)>>,
     req: HttpRequest,
     stream: web::Payload,
     p2p_handler: Data<SharedHandler>,
     local_peer_id: Data<PeerId>,
     request_id_client_session_mapping: AppState<Sender<HttpProxyResponse>>,
-) -> impl Responder {
+    selector: Data<ProviderSelector>,
+    breakers: Data<CircuitBreakers>,
+) -> Result<HttpResponse, Error> {
     info!("ClientSideGateway: Receive Websocket request: {:?}", req);
 
-    let (req_info, remote_peer_id) =
-        prepare_for_sending_p2p_transaction(query_args, web::Bytes::new(), req.clone(), true);
+    let (req_info, service, remote_peer_id) = prepare_for_sending_p2p_transaction(
+        service_data,
+        query_args,
+        web::Bytes::new(),
+        &req,
+        true,
+        &p2p_handler,
+        &local_peer_id,
+        &breakers,
+    )
+    .await?;
 
     info!("ClientSideGateway: Req info: {:?}", req_info);
     info!("ClientSideGateway: remote peer: {:?}", remote_peer_id);
 
+    // Invalid handshake of client is rejected before opening session on service side
+    ws::handshake(&req)?;
+
+    if remote_peer_id == **local_peer_id {
+        return forward_to_local_ws_service(
+            &req,
+            stream,
+            req_info,
+            service,
+            p2p_handler,
+            **local_peer_id,
+            request_id_client_session_mapping,
+            selector,
+            breakers,
+        )
+        .await;
+    }
+
     let (resp_sender, mut resp_receiver): (Sender<HttpProxyResponse>, Receiver<HttpProxyResponse>) =
         mpsc::channel(0);
 
@@ -161,57 +537,237 @@ pub(crate) async fn forward_ws_proxy_request(
         request_id_client_session_mapping.as_ref()
     );
 
-    // Create websocket session between ClientSideGateway and Client
+    // Session actor runs once handshake of client is completed, data of service arriving
+    // before that is queued for it
+    let request_id = req_info.request_id.clone();
+    let (frame_sender, frame_receiver) = mpsc::channel(WS_FRAME_QUEUE_SIZE);
     let client_ws_actor = ClientSideWsActor {
-        req_info,
-        service_peer_id: remote_peer_id,
-        p2p_handler,
-        request_id_client_session_mapping,
+        req_info: req_info.clone(),
+        service_peer_id: Some(remote_peer_id),
+        p2p_handler: p2p_handler.clone(),
+        request_id_client_session_mapping: request_id_client_session_mapping.clone(),
+        frame_sender,
+        fragments: Fragments::default(),
+        closed: false,
     };
-    // TODO: Verify whether it is possible to add function to set actor in, and check whether it can pass lifetime check
-    let foo = ws::start_with_addr(client_ws_actor, &req, stream).unwrap();
-    let addr = foo.0;
+    let (addr, client_stream) = ws::WebsocketContext::create_with_addr(client_ws_actor, stream);
 
+    // Frames of service are relayed over stream of the session, only error reported by
+    // service side gateway arrives here, which ends the session
+    let session_addr = addr.clone();
     Arbiter::spawn(async move {
-        warn!("Spawn resp receiver");
-        loop {
-            warn!("Msg Receiver Loop");
-            futures::select! {
-            msg = resp_receiver.next() => {
-                info!("ClientSideWsActor: Receive msg data from p2p channel: {:?}", msg);
-                match msg {
-                    Some(msg) => match msg {
-                        HttpProxyResponse {
-                            is_websocket_resp,
-                            request_id,
-                            status_code,
-                            headers,
-                            body,
-                        } => {
-                            info!("ClientSideWsActor: data: {:?}", body.clone());
-                            addr.do_send(ProxyData{
-                                request_id: "".to_string(),
-                                is_binary: false,
-                                data: body,
-                            });
-                        }
-                    }
-                    _ => {
-                        info!("ClientSideWsActor: Receive msg 2: {:#?}", msg);
-                    }
-                }
-            },
-            complete => {
-                error!("ClientSideWsActor: Future select exit");
-                break;
-            },
-            }
+        while let Some(resp) = resp_receiver.next().await {
+            warn!(
+                "ClientSideWsActor: Session {} failed: {}",
+                resp.request_id,
+                String::from_utf8_lossy(&resp.body)
+            );
+            session_addr.do_send(ProxyData::close(
+                resp.request_id,
+                1011,
+                String::from_utf8_lossy(&resp.body).to_string(),
+            ));
         }
     });
 
-    foo.1
+    // Handshake of client is answered according to the one of provider
+    let mut command_sender = p2p_handler.command_sender.lock().unwrap().clone();
+    // Session on service side is closed if it fails or times out before client session starts
+    let mut guard = CloseGuard {
+        command_sender: command_sender.clone(),
+        peer: remote_peer_id,
+        request_id: request_id.clone(),
+        finished: false,
+    };
+    let (accept_sender, accept_receiver) = oneshot::channel();
+    let accepted = async {
+        command_sender
+            .send(Command::SendRequest {
+                peer: remote_peer_id,
+                info: req_info.clone(),
+                sender: accept_sender,
+            })
+            .await
+            .map_err(|e| GatewayError::BadGateway(e.to_string()))?;
+        accept_receiver
+            .await
+            .map_err(|_| GatewayError::BadGateway(String::from("Network event loop is stopped")))?
+    };
+    let connect_timeout = service.timeouts.clone().unwrap_or_default().connect();
+    let handshake = match timeout(connect_timeout, accepted).await {
+        Ok(handshake) => handshake,
+        Err(_) => Err(GatewayError::GatewayTimeout(String::from(
+            "Service gateway did not answer the handshake in time",
+        ))),
+    };
+    let handshake = match handshake {
+        Ok(handshake) => handshake,
+        Err(e) => {
+            delete(request_id_client_session_mapping, request_id);
+            return Err(e.into());
+        }
+    };
+
+    // Provider refused the handshake, client gets its response instead
+    if handshake.status_code != StatusCode::SWITCHING_PROTOCOLS.as_u16() {
+        info!(
+            "ClientSideGateway: Handshake of {} refused with {}",
+            request_id, handshake.status_code
+        );
+        guard.finished = true;
+        delete(request_id_client_session_mapping, request_id);
+        return Ok(refused_handshake(handshake));
+    }
+    let mut builder = client_handshake(&req, &handshake)?;
+
+    // Frames are relayed in both directions over a stream of the session
+    let (open_sender, open_receiver) = oneshot::channel();
+    let opened = async {
+        command_sender
+            .send(Command::OpenWsStream {
+                peer: remote_peer_id,
+                request_id: request_id.clone(),
+                sender: open_sender,
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+        open_receiver
+            .await
+            .map_err(|_| String::from("Network event loop is stopped"))?
+    };
+    let ws_stream = match timeout(connect_timeout, opened).await {
+        Ok(Ok(ws_stream)) => ws_stream,
+        Ok(Err(e)) => {
+            delete(request_id_client_session_mapping, request_id);
+            return Err(GatewayError::BadGateway(format!("Open ws stream failed: {}", e)).into());
+        }
+        Err(_) => {
+            delete(request_id_client_session_mapping, request_id);
+            return Err(GatewayError::GatewayTimeout(String::from(
+                "Open ws stream to service gateway timed out",
+            ))
+            .into());
+        }
+    };
+    // Client session takes over closing the session from here
+    guard.finished = true;
+    Arbiter::spawn(relay_ws_session(
+        ws_stream,
+        request_id,
+        frame_receiver,
+        addr.recipient(),
+    ));
+
+    Ok(builder.streaming(client_stream))
+}
+
+// Connect client to websocket service registered on this gateway,
+// frames are passed between sessions of client and service directly
+#[allow(clippy::too_many_arguments)]
+async fn forward_to_local_ws_service(
+    req: &HttpRequest,
+    stream: web::Payload,
+    req_info: ProxyRequestInfo,
+    service: ApronService,
+    p2p_handler: Data<SharedHandler>,
+    local_peer_id: PeerId,
+    request_id_client_session_mapping: AppState<Sender<HttpProxyResponse>>,
+    selector: Data<ProviderSelector>,
+    breakers: Data<CircuitBreakers>,
+) -> Result<HttpResponse, Error> {
+    let provider =
+        select_provider(selector, &breakers.providers, &service, "ws").ok_or_else(|| {
+            GatewayError::ServiceUnavailable(format!(
+                "No healthy ws provider for service {}",
+                service.id
+            ))
+        })?;
+    // Session is not kept anywhere else, nothing to be told once it is over
+    let connected = connect_to_ws_service(
+        provider,
+        &service,
+        local_peer_id,
+        req_info.clone(),
+        p2p_handler.clone(),
+        None,
+    )
+    .await;
+    let (service_addr, service_frames, handshake) = match connected {
+        Ok(connected) => connected,
+        Err(handshake) => {
+            info!(
+                "ClientSideGateway: Handshake of {} refused with {}",
+                req_info.request_id, handshake.status_code
+            );
+            return Ok(refused_handshake(handshake));
+        }
+    };
+
+    let request_id = req_info.request_id.clone();
+    let mut builder = match client_handshake(req, &handshake) {
+        Ok(builder) => builder,
+        Err(e) => {
+            service_addr.do_send(ProxyData::close(
+                request_id,
+                1011,
+                String::from("Client handshake failed"),
+            ));
+            return Err(e);
+        }
+    };
+    let (frame_sender, frame_receiver) = mpsc::channel(WS_FRAME_QUEUE_SIZE);
+    let client_ws_actor = ClientSideWsActor {
+        req_info,
+        service_peer_id: None,
+        p2p_handler,
+        request_id_client_session_mapping,
+        frame_sender,
+        fragments: Fragments::default(),
+        closed: false,
+    };
+    let (addr, client_stream) = ws::WebsocketContext::create_with_addr(client_ws_actor, stream);
+    Arbiter::spawn(pipe_ws_session(
+        request_id.clone(),
+        frame_receiver,
+        service_addr.recipient(),
+    ));
+    Arbiter::spawn(pipe_ws_session(
+        request_id,
+        service_frames,
+        addr.recipient(),
+    ));
+    Ok(builder.streaming(client_stream))
+}
 
-    // let proxy_resp = resp_receiver.recv().unwrap();
-    //
-    // HttpResponse::Ok().body(proxy_resp.body)
+// Provider refused the handshake, client gets its response instead,
+// without headers only meaningful to the upstream connection
+fn refused_handshake(handshake: HttpProxyResponse) -> HttpResponse {
+    to_client_response(handshake, None, None)
+}
+
+// Answer handshake of client according to the one accepted by provider. Subprotocol chosen
+// by provider is chosen for client too, other headers of provider are passed as well
+fn client_handshake(
+    req: &HttpRequest,
+    handshake: &HttpProxyResponse,
+) -> Result<HttpResponseBuilder, Error> {
+    let protocol = handshake
+        .headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("sec-websocket-protocol"))
+        .map(|(_, value)| String::from_utf8_lossy(value).trim().to_string());
+    let mut builder = match &protocol {
+        Some(protocol) => ws::handshake_with_protocols(req, &[protocol.as_str()])?,
+        None => ws::handshake(req)?,
+    };
+    for (key, value) in handshake.headers.iter() {
+        if key.eq_ignore_ascii_case("content-length")
+            || is_hop_by_hop_header(key, None)
+            || is_ws_handshake_header(key)
+        {
+            continue;
+        }
+        builder.header(key.as_str(), value.clone());
+    }
+    Ok(builder)
 }
@@ -0,0 +1,102 @@
+use std::fmt;
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+
+/// Failure of a proxied request, rendered to client as JSON with matching status.
+/// It is also sent between gateways, so the service side can report failures
+/// to the client side gateway waiting for the response.
+#[derive(Deserialize, Debug, Serialize, PartialEq, Clone)]
+pub enum GatewayError {
+    // Request from client is malformed
+    BadRequest(String),
+    // Client is not identified
+    Unauthorized(String),
+    // Service or session doesn't exist
+    NotFound(String),
+    // Remote gateway or provider failed
+    BadGateway(String),
+    // No gateway or provider can serve the request now
+    ServiceUnavailable(String),
+    // Remote gateway or provider didn't respond in time
+    GatewayTimeout(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+    message: &'a str,
+}
+
+impl GatewayError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            GatewayError::BadRequest(_) => "bad_request",
+            GatewayError::Unauthorized(_) => "unauthorized",
+            GatewayError::NotFound(_) => "not_found",
+            GatewayError::BadGateway(_) => "bad_gateway",
+            GatewayError::ServiceUnavailable(_) => "service_unavailable",
+            GatewayError::GatewayTimeout(_) => "gateway_timeout",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            GatewayError::BadRequest(message)
+            | GatewayError::Unauthorized(message)
+            | GatewayError::NotFound(message)
+            | GatewayError::BadGateway(message)
+            | GatewayError::ServiceUnavailable(message)
+            | GatewayError::GatewayTimeout(message) => message,
+        }
+    }
+
+    /// JSON body sent to client.
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec(&ErrorBody {
+            error: self.kind(),
+            message: self.message(),
+        })
+        .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for GatewayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind(), self.message())
+    }
+}
+
+impl ResponseError for GatewayError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            GatewayError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            GatewayError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            GatewayError::NotFound(_) => StatusCode::NOT_FOUND,
+            GatewayError::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            GatewayError::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            GatewayError::GatewayTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("application/json")
+            .body(self.to_json())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_status_and_body() {
+        let error = GatewayError::GatewayTimeout(String::from("Provider did not respond in time"));
+        assert_eq!(error.status_code(), StatusCode::GATEWAY_TIMEOUT);
+        let body: serde_json::Value = serde_json::from_slice(&error.to_json()).unwrap();
+        assert_eq!(body["error"], "gateway_timeout");
+        assert_eq!(body["message"], "Provider did not respond in time");
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::env;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::forward_service_models::MultiMap;
+
+// Only environment variables named with this prefix can be used in header values,
+// so a service owner can't read arbitrary variables of the gateway
+const ENV_PREFIX: &str = "APRON_HDR_";
+
+/// Header rewriting of a service, applied by the gateway serving it.
+#[derive(Deserialize, Debug, Serialize, PartialEq, Clone, Default)]
+pub struct HeaderRules {
+    // Applied to requests before sent to provider
+    pub request: Option<HeaderRuleSet>,
+    // Applied to responses of provider before sent back to client
+    pub response: Option<HeaderRuleSet>,
+}
+
+impl HeaderRules {
+    pub fn apply_to_request(&self, headers: &mut MultiMap<Vec<u8>>) {
+        if let Some(rules) = &self.request {
+            rules.apply(headers, true);
+        }
+    }
+
+    // Variables are not expanded in responses, which are sent back to clients
+    pub fn apply_to_response(&self, headers: &mut MultiMap<Vec<u8>>) {
+        if let Some(rules) = &self.response {
+            rules.apply(headers, false);
+        }
+    }
+}
+
+/// Rules applied to headers in order of remove, add and set.
+/// In request rules, `${NAME}` in values is replaced with environment variable of the gateway serving
+/// the service, so secrets such as upstream api keys are neither seen by clients nor shared with other
+/// gateways. Only variables named with `APRON_HDR_` prefix are expanded, others are replaced with empty string.
+#[derive(Deserialize, Debug, Serialize, PartialEq, Clone, Default)]
+pub struct HeaderRuleSet {
+    pub remove: Option<Vec<String>>,
+    // Headers added if not present
+    pub add: Option<HashMap<String, String>>,
+    // Headers added or replaced
+    pub set: Option<HashMap<String, String>>,
+}
+
+impl HeaderRuleSet {
+    fn apply(&self, headers: &mut MultiMap<Vec<u8>>, expand_vars: bool) {
+        let value_of = |value: &str| {
+            if expand_vars {
+                expand_env(value).into_bytes()
+            } else {
+                value.as_bytes().to_vec()
+            }
+        };
+        for name in self.remove.iter().flatten() {
+            headers.retain(|key, _| !key.eq_ignore_ascii_case(name));
+        }
+        for (name, value) in self.add.iter().flatten() {
+            if !headers
+                .iter()
+                .any(|(key, _)| key.eq_ignore_ascii_case(name))
+            {
+                headers.append(name.to_ascii_lowercase(), value_of(value));
+            }
+        }
+        for (name, value) in self.set.iter().flatten() {
+            headers.set(name.to_ascii_lowercase(), value_of(value));
+        }
+    }
+}
+
+// Replace `${NAME}` with value of environment variable,
+// missing ones and ones without the allowed prefix are replaced with empty string
+fn expand_env(value: &str) -> String {
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        expanded.push_str(&rest[..start]);
+        let name = &rest[start + 2..end];
+        if !name.starts_with(ENV_PREFIX) {
+            warn!(
+                "Environment variable {} used in header is not allowed, name should start with {}",
+                name, ENV_PREFIX
+            );
+            rest = &rest[end + 1..];
+            continue;
+        }
+        match env::var(name) {
+            Ok(var) => expanded.push_str(&var),
+            Err(e) => warn!(
+                "Environment variable {} used in header is not usable: {}",
+                name, e
+            ),
+        }
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_headers(headers: Vec<(&str, &str)>) -> MultiMap<Vec<u8>> {
+        MultiMap::from(
+            headers
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value.as_bytes().to_vec()))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[test]
+    fn test_apply_rules() {
+        env::set_var("APRON_HDR_TEST_UPSTREAM_KEY", "secret");
+        let rules = HeaderRuleSet {
+            remove: Some(vec!["Authorization".to_string()]),
+            add: Some(
+                vec![
+                    ("accept".to_string(), "application/json".to_string()),
+                    ("x-client".to_string(), "apron".to_string()),
+                ]
+                .into_iter()
+                .collect(),
+            ),
+            set: Some(
+                vec![(
+                    "X-Api-Key".to_string(),
+                    "Key ${APRON_HDR_TEST_UPSTREAM_KEY}".to_string(),
+                )]
+                .into_iter()
+                .collect(),
+            ),
+        };
+
+        let mut headers = to_headers(vec![
+            ("authorization", "Bearer client"),
+            ("accept", "text/plain"),
+            ("x-api-key", "from client"),
+            ("x-api-key", "also from client"),
+        ]);
+        rules.apply(&mut headers, true);
+
+        let headers: Vec<(&str, &[u8])> = headers
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_slice()))
+            .collect();
+        assert_eq!(
+            headers,
+            vec![
+                ("accept", &b"text/plain"[..]),
+                ("x-client", &b"apron"[..]),
+                ("x-api-key", &b"Key secret"[..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand_only_allowed_vars_in_requests() {
+        env::set_var("APRON_HDR_TEST_TOKEN", "token");
+        env::set_var("APRON_TEST_PRIVATE", "private");
+        let set_rules = || HeaderRuleSet {
+            set: Some(
+                vec![(
+                    "x-vars".to_string(),
+                    "${APRON_HDR_TEST_TOKEN}/${APRON_TEST_PRIVATE}".to_string(),
+                )]
+                .into_iter()
+                .collect(),
+            ),
+            ..HeaderRuleSet::default()
+        };
+        let rules = HeaderRules {
+            request: Some(set_rules()),
+            response: Some(set_rules()),
+        };
+
+        let mut headers = to_headers(vec![]);
+        rules.apply_to_request(&mut headers);
+        assert_eq!(headers.get("x-vars"), Some(&b"token/".to_vec()));
+
+        let mut headers = to_headers(vec![]);
+        rules.apply_to_response(&mut headers);
+        assert_eq!(
+            headers.get("x-vars"),
+            Some(&b"${APRON_HDR_TEST_TOKEN}/${APRON_TEST_PRIVATE}".to_vec())
+        );
+    }
+}
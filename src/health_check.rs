@@ -0,0 +1,390 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::web::Data;
+use async_std::future::timeout;
+use async_std::net::TcpStream;
+use awc::Client;
+use futures::channel::mpsc;
+use futures::future::join_all;
+use futures::SinkExt;
+use libp2p::PeerId;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::load_balancer::{provider_key, ProviderSelector};
+use crate::network::Command;
+use crate::service::{ApronService, ApronServiceProvider};
+use crate::state::{all, delete, get, set, values, AppState};
+
+const DEFAULT_INTERVAL_SECS: u64 = 10;
+const DEFAULT_TIMEOUT_SECS: u64 = 3;
+const DEFAULT_UNHEALTHY_THRESHOLD: u32 = 3;
+const DEFAULT_HEALTHY_THRESHOLD: u32 = 2;
+
+// Interval of checking whether any provider is due for a probe
+const CHECK_TICK: Duration = Duration::from_secs(1);
+// Health of local providers is gossiped again this often even if unchanged, to keep it fresh on peers
+const REPUBLISH_INTERVAL: Duration = Duration::from_secs(60);
+// Health gossiped by a peer is dropped if not refreshed in time, as the peer may be gone
+const REMOTE_HEALTH_TTL: Duration = Duration::from_secs(180);
+
+#[derive(Deserialize, Debug, Serialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheckKind {
+    // GET request to provider, any response is treated as healthy,
+    // or only 2xx and 3xx status if path is set
+    Http,
+    // Open TCP connection to provider address
+    Tcp,
+    // Websocket handshake with provider
+    Ws,
+}
+
+/// Health check settings of a provider, all fields are optional.
+#[derive(Deserialize, Debug, Serialize, PartialEq, Clone, Default)]
+pub struct HealthCheckConfig {
+    // Probe type, derived from provider schema if not set
+    pub kind: Option<HealthCheckKind>,
+    // Health endpoint requested by http probe, provider root is requested if not set
+    pub path: Option<String>,
+    pub interval_secs: Option<u64>,
+    pub timeout_secs: Option<u64>,
+    // Consecutive failures to mark provider down
+    pub unhealthy_threshold: Option<u32>,
+    // Consecutive successes to mark provider up again
+    pub healthy_threshold: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Serialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Unknown,
+    Up,
+    Down,
+}
+
+/// Health of one provider, saved locally and gossiped to other gateways.
+#[derive(Deserialize, Debug, Serialize, PartialEq, Clone)]
+pub struct ProviderHealth {
+    pub service_id: String,
+    pub provider_id: Option<String>,
+    pub peer_id: String,
+    pub status: HealthStatus,
+    pub consecutive_failures: u32,
+    pub consecutive_successes: u32,
+    pub last_checked_at: u64,
+    pub last_error: Option<String>,
+}
+
+impl ProviderHealth {
+    /// Key of the provider in health data, same as the key used by provider selector.
+    pub fn key(&self) -> String {
+        format!(
+            "{}/{}",
+            self.service_id,
+            self.provider_id.clone().unwrap_or_default()
+        )
+    }
+
+    fn is_due(&self, interval: Duration) -> bool {
+        now_secs().saturating_sub(self.last_checked_at) >= interval.as_secs()
+    }
+
+    /// Record probe result, returns true if status is changed.
+    fn record(&mut self, result: Result<(), String>, config: &HealthCheckConfig) -> bool {
+        let old_status = self.status;
+        self.last_checked_at = now_secs();
+        match result {
+            Ok(()) => {
+                self.consecutive_failures = 0;
+                self.consecutive_successes += 1;
+                self.last_error = None;
+                if self.status == HealthStatus::Unknown
+                    || self.consecutive_successes
+                        >= config
+                            .healthy_threshold
+                            .unwrap_or(DEFAULT_HEALTHY_THRESHOLD)
+                {
+                    self.status = HealthStatus::Up;
+                }
+            }
+            Err(e) => {
+                self.consecutive_successes = 0;
+                self.consecutive_failures += 1;
+                self.last_error = Some(e);
+                if self.consecutive_failures
+                    >= config
+                        .unhealthy_threshold
+                        .unwrap_or(DEFAULT_UNHEALTHY_THRESHOLD)
+                {
+                    self.status = HealthStatus::Down;
+                }
+            }
+        }
+        self.status != old_status
+    }
+}
+
+/// Health of providers of remote services, gossiped by the gateways owning them.
+/// Kept apart from health of local providers, which is checked by this gateway.
+#[derive(Debug, Default)]
+pub struct RemoteHealth {
+    // Keyed by provider key, with the time received
+    entries: Mutex<HashMap<String, (ProviderHealth, Instant)>>,
+}
+
+impl RemoteHealth {
+    pub fn update(&self, health: ProviderHealth) {
+        let mut entries = self.entries.lock().expect("Could not acquire lock");
+        entries.retain(|_, (_, received_at)| received_at.elapsed() < REMOTE_HEALTH_TTL);
+        entries.insert(health.key(), (health, Instant::now()));
+    }
+
+    /// Health of providers of the service, which is not expired.
+    pub fn of_service(&self, service_id: &str) -> Vec<ProviderHealth> {
+        let entries = self.entries.lock().expect("Could not acquire lock");
+        entries
+            .values()
+            .filter(|(health, received_at)| {
+                health.service_id == service_id && received_at.elapsed() < REMOTE_HEALTH_TTL
+            })
+            .map(|(health, _)| health.clone())
+            .collect()
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+async fn probe(provider: &ApronServiceProvider, config: &HealthCheckConfig) -> Result<(), String> {
+    let probe_timeout = Duration::from_secs(config.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS));
+    let kind = config.kind.unwrap_or_else(|| {
+        if provider.schema.as_deref().unwrap_or("").starts_with("ws") {
+            HealthCheckKind::Ws
+        } else {
+            HealthCheckKind::Http
+        }
+    });
+
+    let url = url::Url::parse(&provider.url()).map_err(|e| e.to_string())?;
+    match kind {
+        HealthCheckKind::Http => {
+            // Root of provider may well answer 404 or 401, so status is only
+            // checked for a configured health endpoint
+            let path = match &config.path {
+                Some(path) => path,
+                None => "/",
+            };
+            let check_url = url.join(path).map_err(|e| e.to_string())?;
+            let resp = Client::new()
+                .get(check_url.as_str())
+                .timeout(probe_timeout)
+                .send()
+                .await
+                .map_err(|e| e.to_string())?;
+            if config.path.is_none() || resp.status().is_success() || resp.status().is_redirection()
+            {
+                Ok(())
+            } else {
+                Err(format!("Unexpected status: {}", resp.status()))
+            }
+        }
+        HealthCheckKind::Tcp => {
+            let host = url.host_str().ok_or("Provider url has no host")?;
+            let port = url
+                .port_or_known_default()
+                .ok_or("Provider url has no port")?;
+            timeout(probe_timeout, TcpStream::connect((host, port)))
+                .await
+                .map_err(|e| e.to_string())?
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }
+        HealthCheckKind::Ws => timeout(probe_timeout, Client::new().ws(url.as_str()).connect())
+            .await
+            .map_err(|e| e.to_string())?
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+    }
+}
+
+async fn publish_health(command_sender: &mut mpsc::Sender<Command>, health: &ProviderHealth) {
+    let result = command_sender
+        .send(Command::PublishHealth {
+            data: serde_json::to_vec(health).unwrap(),
+        })
+        .await;
+    if let Err(e) = result {
+        warn!(
+            "Publish health of provider {} failed: {:?}",
+            health.key(),
+            e
+        );
+    }
+}
+
+/// Periodically probes providers of services registered on this gateway,
+/// updates their status in health data and provider selector,
+/// and gossips status changes to other gateways, as well as all status once in a while.
+/// Should be spawned in actix runtime since the probes use awc client.
+pub async fn health_check_loop(
+    service_data: AppState<ApronService>,
+    health_data: AppState<ProviderHealth>,
+    provider_selector: Data<ProviderSelector>,
+    local_peer_id: PeerId,
+    mut command_sender: mpsc::Sender<Command>,
+) {
+    info!("Provider health checker started");
+    let local_peer_id = local_peer_id.to_base58();
+    let mut published_at = Instant::now();
+    loop {
+        async_std::task::sleep(CHECK_TICK).await;
+
+        let mut local_keys = HashSet::new();
+        let mut due_checks = Vec::new();
+        for service in values(service_data.clone()).unwrap_or_default() {
+            if service.peer_id.as_ref() != Some(&local_peer_id) || !service.is_active() {
+                continue;
+            }
+            for provider in service.providers.clone().unwrap_or_default() {
+                let key = provider_key(&service.id, &provider);
+                local_keys.insert(key.clone());
+
+                let config = provider.health_check.clone().unwrap_or_default();
+                let health = get(health_data.clone(), key.clone()).unwrap_or(ProviderHealth {
+                    service_id: service.id.clone(),
+                    provider_id: provider.id.clone().or_else(|| provider.base_url.clone()),
+                    peer_id: local_peer_id.clone(),
+                    status: HealthStatus::Unknown,
+                    consecutive_failures: 0,
+                    consecutive_successes: 0,
+                    last_checked_at: 0,
+                    last_error: None,
+                });
+                let interval =
+                    Duration::from_secs(config.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS));
+                if health.is_due(interval) {
+                    due_checks.push((key, provider, config, health));
+                }
+            }
+        }
+
+        // Forget providers removed from local services
+        for (key, health) in all(health_data.clone()).unwrap_or_default() {
+            if health.peer_id == local_peer_id && !local_keys.contains(&key) {
+                delete(health_data.clone(), key.clone());
+                provider_selector.set_healthy(&key, true);
+            }
+        }
+
+        let results = join_all(
+            due_checks
+                .iter()
+                .map(|(_, provider, config, _)| probe(provider, config)),
+        )
+        .await;
+
+        for ((key, _, config, mut health), result) in due_checks.into_iter().zip(results) {
+            if let Err(e) = &result {
+                warn!("Health check of provider {} failed: {}", key, e);
+            }
+            let changed = health.record(result, &config);
+            set(health_data.clone(), key.clone(), health.clone());
+
+            if changed {
+                info!("Provider {} is {:?} now", key, health.status);
+                provider_selector.set_healthy(&key, health.status != HealthStatus::Down);
+                publish_health(&mut command_sender, &health).await;
+            }
+        }
+
+        if published_at.elapsed() >= REPUBLISH_INTERVAL {
+            published_at = Instant::now();
+            for health in values(health_data.clone()).unwrap_or_default() {
+                publish_health(&mut command_sender, &health).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_health() -> ProviderHealth {
+        ProviderHealth {
+            service_id: String::from("service"),
+            provider_id: Some(String::from("provider")),
+            peer_id: String::from("peer"),
+            status: HealthStatus::Unknown,
+            consecutive_failures: 0,
+            consecutive_successes: 0,
+            last_checked_at: 0,
+            last_error: None,
+        }
+    }
+
+    fn fail() -> Result<(), String> {
+        Err(String::from("refused"))
+    }
+
+    #[test]
+    fn test_first_success_marks_up() {
+        let mut health = new_health();
+        assert!(health.record(Ok(()), &HealthCheckConfig::default()));
+        assert_eq!(health.status, HealthStatus::Up);
+        assert!(health.last_checked_at > 0);
+    }
+
+    #[test]
+    fn test_down_after_unhealthy_threshold() {
+        let config = HealthCheckConfig::default();
+        let mut health = new_health();
+        health.record(Ok(()), &config);
+
+        for _ in 1..DEFAULT_UNHEALTHY_THRESHOLD {
+            assert!(!health.record(fail(), &config));
+            assert_eq!(health.status, HealthStatus::Up);
+        }
+        assert!(health.record(fail(), &config));
+        assert_eq!(health.status, HealthStatus::Down);
+        assert_eq!(health.consecutive_failures, DEFAULT_UNHEALTHY_THRESHOLD);
+        assert_eq!(health.last_error.as_deref(), Some("refused"));
+
+        // A success in between starts counting again
+        let mut health = new_health();
+        health.record(Ok(()), &config);
+        for _ in 1..DEFAULT_UNHEALTHY_THRESHOLD {
+            health.record(fail(), &config);
+        }
+        health.record(Ok(()), &config);
+        health.record(fail(), &config);
+        assert_eq!(health.status, HealthStatus::Up);
+    }
+
+    #[test]
+    fn test_up_after_healthy_threshold() {
+        let config = HealthCheckConfig {
+            unhealthy_threshold: Some(1),
+            healthy_threshold: Some(3),
+            ..HealthCheckConfig::default()
+        };
+        let mut health = new_health();
+        assert!(health.record(fail(), &config));
+        assert_eq!(health.status, HealthStatus::Down);
+
+        assert!(!health.record(Ok(()), &config));
+        assert!(!health.record(Ok(()), &config));
+        assert_eq!(health.status, HealthStatus::Down);
+        assert!(health.record(Ok(()), &config));
+        assert_eq!(health.status, HealthStatus::Up);
+        assert_eq!(health.consecutive_failures, 0);
+        assert_eq!(health.last_error, None);
+    }
+}
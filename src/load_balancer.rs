@@ -0,0 +1,291 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use actix_web::web::Data;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::circuit_breaker::CircuitBreaker;
+use crate::service::{ApronService, ApronServiceProvider};
+
+/// Strategy used to pick one provider when a service has several of them.
+#[derive(Deserialize, Debug, Serialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalanceStrategy {
+    RoundRobin,
+    Weighted,
+    LeastOutstanding,
+    Random,
+}
+
+impl Default for LoadBalanceStrategy {
+    fn default() -> Self {
+        LoadBalanceStrategy::RoundRobin
+    }
+}
+
+/// Keeps the selection state of all providers handled by this gateway.
+#[derive(Debug, Default)]
+pub struct ProviderSelector {
+    // Counter for round robin and weighted selection, keyed by service id and schema
+    counters: Mutex<HashMap<String, usize>>,
+    // Requests currently being processed by provider, keyed by provider key
+    outstanding: Mutex<HashMap<String, usize>>,
+    // Providers which should not receive requests, keyed by provider key
+    unhealthy: Mutex<HashSet<String>>,
+}
+
+/// Provider picked for one request, the outstanding request counter of the
+/// provider is released while the lease is dropped.
+#[derive(Debug)]
+pub struct ProviderLease {
+    selector: Data<ProviderSelector>,
+    pub key: String,
+    pub provider: ApronServiceProvider,
+}
+
+impl Drop for ProviderLease {
+    fn drop(&mut self) {
+        let mut outstanding = self
+            .selector
+            .outstanding
+            .lock()
+            .expect("Could not acquire lock");
+        if let Some(count) = outstanding.get_mut(&self.key) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+/// Key used to identify provider of a service in selector.
+pub fn provider_key(service_id: &str, provider: &ApronServiceProvider) -> String {
+    let provider_id = provider
+        .id
+        .clone()
+        .or_else(|| provider.base_url.clone())
+        .unwrap_or_default();
+    format!("{}/{}", service_id, provider_id)
+}
+
+impl ProviderSelector {
+    pub fn set_healthy(&self, key: &str, is_healthy: bool) {
+        let mut unhealthy = self.unhealthy.lock().expect("Could not acquire lock");
+        if is_healthy {
+            unhealthy.remove(key);
+        } else {
+            unhealthy.insert(key.to_string());
+        }
+    }
+
+    pub fn is_healthy(&self, key: &str) -> bool {
+        !self
+            .unhealthy
+            .lock()
+            .expect("Could not acquire lock")
+            .contains(key)
+    }
+
+    fn next_counter(&self, key: String) -> usize {
+        let mut counters = self.counters.lock().expect("Could not acquire lock");
+        let counter = counters.entry(key).or_insert(0);
+        let current = *counter;
+        *counter = counter.wrapping_add(1);
+        current
+    }
+}
+
+/// Select a healthy provider of the service whose schema starts with `schema`,
+/// using the load balance strategy configured for the service.
+/// Providers whose circuit is open in `breakers` are skipped.
+/// Returns None if no usable provider exists.
+pub fn select_provider(
+    selector: Data<ProviderSelector>,
+    breakers: &CircuitBreaker,
+    service: &ApronService,
+    schema: &str,
+) -> Option<ProviderLease> {
+    select_provider_except(selector, breakers, service, schema, &[])
+}
+
+/// Same as `select_provider`, but skips providers whose key is in `excluded`,
+/// such as ones already failed for the request.
+pub fn select_provider_except(
+    selector: Data<ProviderSelector>,
+    breakers: &CircuitBreaker,
+    service: &ApronService,
+    schema: &str,
+    excluded: &[String],
+) -> Option<ProviderLease> {
+    let candidates: Vec<(String, &ApronServiceProvider)> = service
+        .providers
+        .as_ref()?
+        .iter()
+        .filter(|p| p.base_url.is_some())
+        .filter(|p| p.schema.as_ref().map_or(false, |s| s.starts_with(schema)))
+        .map(|p| (provider_key(&service.id, p), p))
+        .filter(|(key, _)| {
+            selector.is_healthy(key) && !breakers.is_open(key) && !excluded.contains(key)
+        })
+        .collect();
+
+    if candidates.is_empty() {
+        return None;
+    }
+
+    let counter_key = format!("{}/{}", service.id, schema);
+    let index = match service.lb_strategy.unwrap_or_default() {
+        LoadBalanceStrategy::RoundRobin => selector.next_counter(counter_key) % candidates.len(),
+        LoadBalanceStrategy::Weighted => {
+            let weights: Vec<usize> = candidates
+                .iter()
+                .map(|(_, p)| p.weight.unwrap_or(1) as usize)
+                .collect();
+            let total: usize = weights.iter().sum();
+            if total == 0 {
+                selector.next_counter(counter_key) % candidates.len()
+            } else {
+                let mut point = selector.next_counter(counter_key) % total;
+                let mut index = 0;
+                for (i, weight) in weights.iter().enumerate() {
+                    if point < *weight {
+                        index = i;
+                        break;
+                    }
+                    point -= weight;
+                }
+                index
+            }
+        }
+        LoadBalanceStrategy::LeastOutstanding => {
+            let outstanding = selector.outstanding.lock().expect("Could not acquire lock");
+            candidates
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, (key, _))| outstanding.get(key).cloned().unwrap_or(0))
+                .map(|(i, _)| i)
+                .unwrap_or(0)
+        }
+        LoadBalanceStrategy::Random => thread_rng().gen_range(0..candidates.len()),
+    };
+
+    let (key, provider) = candidates[index].clone();
+    *selector
+        .outstanding
+        .lock()
+        .expect("Could not acquire lock")
+        .entry(key.clone())
+        .or_insert(0) += 1;
+
+    Some(ProviderLease {
+        selector,
+        key,
+        provider: provider.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{new_provider, new_service};
+
+    fn weighted_provider(id: &str, weight: u32) -> ApronServiceProvider {
+        ApronServiceProvider {
+            weight: Some(weight),
+            ..new_provider(id, &format!("{}.local:8080", id))
+        }
+    }
+
+    // Service with providers p1 and p2, weighted 1 and 3
+    fn balanced_service(strategy: LoadBalanceStrategy) -> ApronService {
+        ApronService {
+            lb_strategy: Some(strategy),
+            ..new_service(vec![weighted_provider("p1", 1), weighted_provider("p2", 3)])
+        }
+    }
+
+    fn select_ids(
+        selector: &Data<ProviderSelector>,
+        service: &ApronService,
+        n: usize,
+    ) -> Vec<String> {
+        (0..n)
+            .map(|_| {
+                select_provider(
+                    selector.clone(),
+                    &CircuitBreaker::default(),
+                    service,
+                    "http",
+                )
+                .unwrap()
+                .provider
+                .id
+                .clone()
+                .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_round_robin_skips_unhealthy() {
+        let selector = Data::new(ProviderSelector::default());
+        let service = balanced_service(LoadBalanceStrategy::RoundRobin);
+        assert_eq!(
+            select_ids(&selector, &service, 4),
+            vec!["p1", "p2", "p1", "p2"]
+        );
+
+        selector.set_healthy("test_service/p1", false);
+        assert_eq!(select_ids(&selector, &service, 2), vec!["p2", "p2"]);
+
+        selector.set_healthy("test_service/p2", false);
+        assert!(select_provider(
+            selector.clone(),
+            &CircuitBreaker::default(),
+            &service,
+            "http"
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_skips_open_circuit() {
+        let selector = Data::new(ProviderSelector::default());
+        let breakers = CircuitBreaker::default();
+        let service = balanced_service(LoadBalanceStrategy::RoundRobin);
+        for _ in 0..5 {
+            breakers.record("test_service/p1", Err("refused".to_string()));
+        }
+        for _ in 0..2 {
+            let lease = select_provider(selector.clone(), &breakers, &service, "http").unwrap();
+            assert_eq!(lease.provider.id.as_deref(), Some("p2"));
+        }
+
+        for _ in 0..5 {
+            breakers.record("test_service/p2", Err("refused".to_string()));
+        }
+        assert!(select_provider(selector.clone(), &breakers, &service, "http").is_none());
+    }
+
+    #[test]
+    fn test_weighted_and_least_outstanding() {
+        let selector = Data::new(ProviderSelector::default());
+        let service = balanced_service(LoadBalanceStrategy::Weighted);
+        assert_eq!(
+            select_ids(&selector, &service, 4),
+            vec!["p1", "p2", "p2", "p2"]
+        );
+
+        let service = balanced_service(LoadBalanceStrategy::LeastOutstanding);
+        let lease = select_provider(
+            selector.clone(),
+            &CircuitBreaker::default(),
+            &service,
+            "http",
+        )
+        .unwrap();
+        assert_eq!(lease.provider.id.as_deref(), Some("p1"));
+        assert_eq!(select_ids(&selector, &service, 1), vec!["p2"]);
+        drop(lease);
+        assert_eq!(select_ids(&selector, &service, 1), vec!["p1"]);
+    }
+}
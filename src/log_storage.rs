@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use log::{error, info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::state::Storage;
+
+// Rewrite the log while it contains this many records more than live entries
+const COMPACTION_SLACK: usize = 1000;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum LogRecord<K, V> {
+    Set { key: K, value: V },
+    Delete { key: K },
+}
+
+/// Durable storage which appends every change to a JSON lines file,
+/// and replays the file while opened.
+pub struct JsonLogStorage<T> {
+    path: PathBuf,
+    file: File,
+    entries: HashMap<String, T>,
+    // Records in the log file, used to decide when to compact it
+    records: usize,
+}
+
+impl<T> JsonLogStorage<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    /// Open the log file, the file will be created if not exists.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut entries = HashMap::new();
+
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path)?);
+            for (line_no, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<LogRecord<String, T>>(&line) {
+                    Ok(LogRecord::Set { key, value }) => {
+                        entries.insert(key, value);
+                    }
+                    Ok(LogRecord::Delete { key }) => {
+                        entries.remove(&key);
+                    }
+                    // Most likely the last record is partially written while process exited
+                    Err(e) => warn!("Skip invalid record at {:?}:{}: {:?}", path, line_no + 1, e),
+                }
+            }
+        }
+
+        info!("Loaded {} entries from {:?}", entries.len(), path);
+        let file = Self::write_snapshot(&path, &entries)?;
+        Ok(JsonLogStorage {
+            path,
+            file,
+            records: entries.len(),
+            entries,
+        })
+    }
+
+    // Replace the log file with one set record per entry, and return it opened for appending
+    fn write_snapshot(path: &Path, entries: &HashMap<String, T>) -> io::Result<File> {
+        let tmp_path = snapshot_tmp_path(path);
+        {
+            let mut tmp_file = File::create(&tmp_path)?;
+            for (key, value) in entries.iter() {
+                let record: LogRecord<&str, &T> = LogRecord::Set {
+                    key: key.as_str(),
+                    value,
+                };
+                serde_json::to_writer(&mut tmp_file, &record)?;
+                tmp_file.write_all(b"\n")?;
+            }
+            tmp_file.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)?;
+
+        OpenOptions::new().append(true).open(path)
+    }
+
+    fn append(&mut self, record: LogRecord<&str, &T>) {
+        let result = serde_json::to_vec(&record)
+            .map_err(io::Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                self.file.write_all(&line)?;
+                self.file.sync_data()
+            });
+        match result {
+            Ok(()) => self.records += 1,
+            Err(e) => error!("Write record to {:?} failed: {:?}", self.path, e),
+        }
+    }
+
+    // Must be called after entries are updated, as the snapshot is written from them
+    fn compact_if_needed(&mut self) {
+        if self.records > self.entries.len() + COMPACTION_SLACK {
+            match Self::write_snapshot(&self.path, &self.entries) {
+                Ok(file) => {
+                    self.file = file;
+                    self.records = self.entries.len();
+                }
+                Err(e) => error!("Compact {:?} failed: {:?}", self.path, e),
+            }
+        }
+    }
+}
+
+// Snapshot is written next to the log, named after its full file name,
+// so logs whose names only differ in extension don't share it
+fn snapshot_tmp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+impl<T: fmt::Debug> fmt::Debug for JsonLogStorage<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JsonLogStorage")
+            .field("path", &self.path)
+            .field("entries", &self.entries)
+            .finish()
+    }
+}
+
+impl<T> Storage<T> for JsonLogStorage<T>
+where
+    T: Serialize + DeserializeOwned + fmt::Debug + Send,
+{
+    fn get(&self, key: &str) -> Option<&T> {
+        self.entries.get(key)
+    }
+
+    fn insert(&mut self, key: String, value: T) -> Option<T> {
+        self.append(LogRecord::Set {
+            key: key.as_str(),
+            value: &value,
+        });
+        let old = self.entries.insert(key, value);
+        self.compact_if_needed();
+        old
+    }
+
+    fn remove(&mut self, key: &str) -> Option<T> {
+        let old = self.entries.remove(key);
+        if old.is_some() {
+            self.append(LogRecord::Delete { key });
+            self.compact_if_needed();
+        }
+        old
+    }
+
+    fn entries(&self) -> &HashMap<String, T> {
+        &self.entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload_from_log() {
+        let path =
+            std::env::temp_dir().join(format!("apron-log-storage-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        {
+            let mut storage = JsonLogStorage::<String>::open(&path).unwrap();
+            storage.insert("a".to_string(), "1".to_string());
+            storage.insert("b".to_string(), "2".to_string());
+            storage.insert("a".to_string(), "3".to_string());
+            storage.remove("b");
+        }
+
+        let storage = JsonLogStorage::<String>::open(&path).unwrap();
+        assert_eq!(storage.entries().len(), 1);
+        assert_eq!(storage.get("a"), Some(&"3".to_string()));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_reload_after_compaction() {
+        let path = std::env::temp_dir().join(format!(
+            "apron-log-storage-compaction-{}.log",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+
+        // The last write starts compaction
+        let last = COMPACTION_SLACK + 1;
+        {
+            let mut storage = JsonLogStorage::<usize>::open(&path).unwrap();
+            storage.insert("b".to_string(), 0);
+            for i in 0..=last {
+                storage.insert("a".to_string(), i);
+            }
+            assert_eq!(storage.records, storage.entries().len());
+        }
+
+        let storage = JsonLogStorage::<usize>::open(&path).unwrap();
+        assert_eq!(storage.get("a"), Some(&last));
+        assert_eq!(storage.get("b"), Some(&0));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_tmp_path() {
+        assert_eq!(
+            snapshot_tmp_path(Path::new("/data/registry")),
+            PathBuf::from("/data/registry.tmp")
+        );
+        assert_eq!(
+            snapshot_tmp_path(Path::new("/data/registry.announcements")),
+            PathBuf::from("/data/registry.announcements.tmp")
+        );
+    }
+}
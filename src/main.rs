@@ -1,40 +1,64 @@
 use actix_cors::Cors;
-use std::collections::HashMap;
 use std::error::Error;
+use std::path::PathBuf;
 use std::sync::Mutex;
 
-use actix::{Addr, Arbiter};
+use actix::Arbiter;
 use actix_web::{web, web::Data, App, HttpServer};
+use async_std::future::timeout;
 use env_logger::{Builder, Env};
 use futures::channel::mpsc;
 use futures::prelude::*;
+use libp2p::request_response::ResponseChannel;
 use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
-use log::info;
+use log::{error, info, warn};
 use structopt::StructOpt;
 
-use crate::forward_service_actors::ServiceSideWsActor;
+use crate::announcement::{announcements_path, SignedAnnouncement};
+use crate::circuit_breaker::CircuitBreakers;
 // use crate::event_loop::EventLoop;
 use crate::forward_service_models::{HttpProxyResponse, ProxyData};
-use crate::forward_service_utils::connect_to_ws_service;
+use crate::forward_service_utils::{connect_to_ws_service, forward_to_providers, is_streaming_request, WsServiceSession};
+use crate::gateway_error::GatewayError;
+use crate::health_check::{health_check_loop, ProviderHealth, RemoteHealth};
+use crate::load_balancer::ProviderSelector;
 use crate::network::Command;
+use crate::protocol::ProxyResponse;
 use crate::routes::routes;
 use crate::service::{ApronService, SharedHandler};
-use crate::state::new_state;
+use crate::log_storage::JsonLogStorage;
+use crate::state::{new_state, new_state_with};
+use crate::ws_sessions::{SessionKey, WsSessions};
+use crate::ws_stream::relay_ws_session;
 
 use crate::contract::{call, exec};
 
 // mod event_loop;
+mod announcement;
+mod circuit_breaker;
 mod contract;
 mod forward_service;
 mod forward_service_actors;
 mod forward_service_models;
 mod forward_service_utils;
 mod fwd_handlers;
+mod gateway_error;
+mod header_rules;
+mod health_check;
 mod helpers;
+mod load_balancer;
+mod log_storage;
 mod network;
+mod protocol;
+mod retry;
 mod routes;
 mod service;
 mod state;
+#[cfg(test)]
+mod test_fixtures;
+mod timeouts;
+mod ws_sessions;
+mod ws_stream;
 
 // substrate node rpc
 const WS_ENDPOINT: &str = "ws://127.0.0.1:9944";
@@ -45,6 +69,14 @@ const MARKET_ABI_PATH: &str = "./release/services_market.json";
 const STAT_CONTRACT_ADDR: &str = "5FUPaZUs2Vk3RypeK2ozeMyTZbQLraoWFKuAwrer1GPoUv8Y";
 const STAT_ABI_PATH: &str = "./release/services_statistics.json";
 
+// Ws session connected to provider or refused, with channel to reply handshake of provider
+type WsConnected = (
+    SessionKey,
+    Result<WsServiceSession, HttpProxyResponse>,
+    ResponseChannel<ProxyResponse>,
+);
+
+
 #[derive(Debug, StructOpt, Clone)]
 #[structopt(name = "apron gateway")]
 pub struct Opt {
@@ -82,6 +114,12 @@ pub struct Opt {
 
     #[structopt(default_value = "./release/services_statistics.json", long)]
     stat_contract_abi: String,
+
+    /// File to persist service registry, keep registry in memory only if not set.
+    /// Use it with --secret-key-seed so that local services are still owned after restart.
+    /// Signed announcements of services are kept in the same path suffixed with `.announcements`.
+    #[structopt(long, parse(from_os_str))]
+    registry_path: Option<PathBuf>,
 }
 
 fn init_logger() {
@@ -95,7 +133,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
     init_logger();
     let opt = Opt::from_args();
 
-    let mut swarm = network::new(opt.secret_key_seed).await.unwrap();
+    // Create a public/private key pair, either random or based on a seed.
+    let (local_key, _) = helpers::generate_peer_id_from_seed(opt.secret_key_seed);
+
+    let mut swarm = network::new(local_key.clone()).await.unwrap();
 
     // In case the user provided an address of a peer on the CLI, dial it.
     if let Some(to_dial) = opt.clone().peer {
@@ -126,10 +167,18 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let peer_id = swarm.local_peer_id().clone();
 
-    let (mut command_sender, command_receiver) = mpsc::channel(0);
+    let (command_sender, command_receiver) = mpsc::channel(0);
     let (event_sender, mut event_receiver) = mpsc::channel(0);
 
-    let data = new_state::<ApronService>();
+    let data = match opt.registry_path.as_ref() {
+        Some(path) => new_state_with(JsonLogStorage::<ApronService>::open(path)?),
+        None => new_state::<ApronService>(),
+    };
+    // Services synced from peers are forwarded with their announcements, which can't be signed again
+    let announcements = match opt.registry_path.as_ref() {
+        Some(path) => new_state_with(JsonLogStorage::<SignedAnnouncement>::open(announcements_path(path))?),
+        None => new_state::<SignedAnnouncement>(),
+    };
     // let service_peer_mapping = new_state::<PeerId>();
 
 // SBP M2 Should this be made configurable too?
@@ -138,6 +187,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // let req_id_client_session_mapping = Data::new(Mutex::new(mpsc::Sender<HttpProxyResponse>));
     let req_id_client_session_mapping = new_state::<mpsc::Sender<HttpProxyResponse>>();
 
+    let provider_selector = Data::new(ProviderSelector::default());
+    let health_data = new_state::<ProviderHealth>();
+    let remote_health = Data::new(RemoteHealth::default());
+    let circuit_breakers = Data::new(CircuitBreakers::default());
+
     async_std::task::spawn(network::network_event_loop(
         swarm,
         command_receiver,
@@ -146,6 +200,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
         req_id_client_session_mapping.clone(),
         opt.clone(),
         data.clone(),
+        provider_selector.clone(),
+        circuit_breakers.clone(),
+        remote_health.clone(),
+        announcements,
+        local_key,
     ));
 
     let p2p_handler = Data::new(SharedHandler {
@@ -159,11 +218,23 @@ async fn main() -> Result<(), Box<dyn Error>> {
         p2p_handler: p2p_handler.clone(),
         peer_id,
         req_id_client_session_mapping: req_id_client_session_mapping.clone(),
+        circuit_breakers: circuit_breakers.clone(),
+        provider_selector: provider_selector.clone(),
     }
     .start();
 
+    // Probe providers of local services in background
+    Arbiter::spawn(health_check_loop(
+        data.clone(),
+        health_data.clone(),
+        provider_selector.clone(),
+        peer_id,
+        command_sender.clone(),
+    ));
+
     let mgmt_local_peer_id = web::Data::new(peer_id.clone());
     let mgmt_p2p_handler = p2p_handler.clone();
+    let mgmt_circuit_breakers = circuit_breakers.clone();
 
     let mgmt_service = HttpServer::new(move || {
 // SBP M2 Consider less permissive configurations?
@@ -173,6 +244,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
             .app_data(data.clone())
             .app_data(mgmt_p2p_handler.clone())
             .app_data(mgmt_local_peer_id.clone())
+            .app_data(health_data.clone())
+            .app_data(remote_health.clone())
+            .app_data(mgmt_circuit_breakers.clone())
             .configure(routes)
     })
     .bind(opt.mgmt_addr)?
@@ -181,14 +255,16 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // Websocket connect loop, which handles those actions:
     // * receives message sent from network event handler,
     // * processes websocket connection
-    // * forward message to network handler with data_sender
+    // * relays websocket connection over stream of the session opened by client side gateway
     Arbiter::spawn(async move {
-        let mut req_id_ws_addr_mapping: HashMap<String, Addr<ServiceSideWsActor>> = HashMap::new();
-        let (data_sender, mut ws_data_receiver): (
-            mpsc::Sender<ProxyData>,
-            mpsc::Receiver<ProxyData>,
+        let mut ws_sessions = WsSessions::default();
+        // Connections to ws services are made in background, and passed back once done
+        let (connected_sender, mut connected_receiver): (
+            mpsc::Sender<WsConnected>,
+            mpsc::Receiver<WsConnected>,
         ) = mpsc::channel(0);
-        let mut remote_peer_id2: Option<PeerId> = None;
+        // Sessions closed or stopped, told by their actors
+        let (done_sender, mut done_receiver) = mpsc::channel::<(PeerId, String)>(0);
         loop {
             futures::select! {
                 evt = event_receiver.next() => {
@@ -197,45 +273,112 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     match evt {
                         Some(evt) => match evt {
                             network::Event::ProxyRequestToMainLoop {
-                                ws_base,
+                                service,
+                                provider,
                                 info,
                                 remote_peer_id,
+                                channel,
                             } => {
                                 info!(
                                     "ServiceSideGateway: Proxy request received is {:?}",
                                     info.clone().request_id
                                 );
-                                remote_peer_id2 = Some(remote_peer_id.clone());
-                                // TODO: Get ws url from saved service info
-                                let addr = connect_to_ws_service(
-                                    &ws_base,
-                                    remote_peer_id,
-                                    info.clone().request_id,
-                                    p2p_handler.clone(),
-                                    data_sender.clone(),
-                                    command_sender.clone(),
-                                ).await;
-                                req_id_ws_addr_mapping.insert(info.clone().request_id, addr);
-                                info!("ServiceSideGateway: InitWsConn: req_id_ws_addr_mapping keys: {:?}, request_id: {:?}", req_id_ws_addr_mapping.keys(), info.clone().request_id);
+                                let session = (remote_peer_id, info.request_id.clone());
+                                ws_sessions.connecting(session.clone());
+                                let p2p_handler = p2p_handler.clone();
+                                let done_sender = done_sender.clone();
+                                let mut connected_sender = connected_sender.clone();
+                                Arbiter::spawn(async move {
+                                    let connected = connect_to_ws_service(
+                                        provider,
+                                        &service,
+                                        remote_peer_id,
+                                        info,
+                                        p2p_handler,
+                                        Some(done_sender),
+                                    ).await;
+                                    let _ = connected_sender.send((session, connected, channel)).await;
+                                });
                             }
 
-                            network::Event::ProxyDataFromClient {
-                                data,
+                            network::Event::HttpRequestToMainLoop {
+                                service,
+                                provider,
+                                info,
+                                remote_peer_id,
+                                body,
+                                cancel,
+                                channel,
                             } => {
-                                info!(
-                                    "ServiceSideGateway: client side proxy data received {:?}",
-                                    data.data.clone()
-                                );
-                                // TODO: Send data to websocket connection
-                                info!("ServiceSideGateway: WsData: req_id_ws_addr_mapping keys: {:?}, request_id: {:?}", req_id_ws_addr_mapping.keys(), data.request_id.clone());
-                                let service_addr = req_id_ws_addr_mapping.get(&data.request_id).unwrap();
-                                service_addr.do_send(data);
+                                // Each upstream request runs in its own task, the response is sent back
+                                // to the network event loop to reply the client side gateway.
+                                let mut command_sender = command_sender.clone();
+                                let provider_selector = provider_selector.clone();
+                                let circuit_breakers = circuit_breakers.clone();
+                                let forwarding = async move {
+                                    let request_id = info.request_id.clone();
+                                    let upload = body.map(|body| network::body_stream(body, command_sender.clone()));
+                                    // Client side gateway gives up at the same time, no use to wait longer
+                                    let total = service.timeouts.clone().unwrap_or_default().total(is_streaming_request(&info));
+                                    let forwarded = timeout(total, forward_to_providers(info, upload, &service, provider, provider_selector, circuit_breakers)).await;
+                                    // Provider is released once the whole body is sent
+                                    let (resp, download, _provider) = match forwarded {
+                                        Ok((provider, Ok((resp, download)))) => (resp, download, Some(provider)),
+                                        Ok((provider, Err(e))) => {
+                                            error!("Request to provider {} failed: {}", provider.key, e);
+                                            (HttpProxyResponse::with_error(request_id.clone(), &e), None, Some(provider))
+                                        }
+                                        Err(_) => {
+                                            let e = GatewayError::GatewayTimeout(String::from("Request to provider timed out"));
+                                            error!("Request {} failed: {}", request_id, e);
+                                            (HttpProxyResponse::with_error(request_id.clone(), &e), None, None)
+                                        }
+                                    };
+                                    command_sender.send(Command::SendResponse {
+                                        response: ProxyResponse::HttpResponse(resp),
+                                        channel,
+                                    }).await.unwrap();
+
+                                    // Large body follows in chunks, upstream is dropped once client side stops receiving
+                                    if let Some(download) = download {
+                                        if let Err(e) = network::send_body_chunks(command_sender, remote_peer_id, request_id.clone(), download).await {
+                                            warn!("Streaming body of {} to {} stopped: {}", request_id, remote_peer_id, e);
+                                        }
+                                    }
+                                };
+                                // Upstream request and response are dropped if client closed the request
+                                Arbiter::spawn(async move {
+                                    futures::select! {
+                                        _ = Box::pin(forwarding).fuse() => {}
+                                        _ = cancel.fuse() => info!("ServiceSideGateway: Request cancelled by client"),
+                                    }
+                                });
                             }
 
-                            network::Event::ProxyDataFromService {
-                                data,
+                            network::Event::WsStreamFromClient {
+                                request_id,
+                                remote_peer_id,
+                                stream,
+                            } => {
+                                // Peers can only reach sessions opened by themselves, and open stream of each once
+                                let session = (remote_peer_id, request_id.clone());
+                                match ws_sessions.take_frames(&session) {
+                                    Some((addr, frames)) => {
+                                        info!("ServiceSideGateway: Relay ws session {} of {}", request_id, remote_peer_id);
+                                        Arbiter::spawn(relay_ws_session(stream, request_id, frames, addr.recipient()));
+                                    }
+                                    // Stream is dropped, client side gateway closes the session once it ends
+                                    None => warn!("ServiceSideGateway: No ws session to relay for request {} of {}", request_id, remote_peer_id),
+                                }
+                            }
+                            network::Event::SessionClosed {
+                                request_id,
+                                remote_peer_id,
                             } => {
-                                info!("ServiceSideGateway: ProxyDataFromService: {:?}", data)
+                                // Nothing to do for http requests, which have no ws session
+                                let session = (remote_peer_id, request_id.clone());
+                                let close = ProxyData::close(request_id.clone(), 1001, String::from("Client is gone"));
+                                ws_sessions.close(&session, close);
                             }
                         }
                         _ => {
@@ -243,17 +386,33 @@ async fn main() -> Result<(), Box<dyn Error>> {
                         }
                     }
                 }
-                proxy_data = ws_data_receiver.next() => {
-                    match proxy_data {
-                        Some(proxy_data) => {
-                        info!("Msg received in main loop: {:?}", proxy_data.clone());
-                        command_sender.send(Command::SendProxyDataFromService {
-                            peer: remote_peer_id2.unwrap(),
-                            request_id: proxy_data.clone().request_id,
-                            data: bincode::serialize(&proxy_data).unwrap(),
-                        }).await.unwrap();
-                        }
-                        _ => {}
+                connected = connected_receiver.next() => {
+                    if let Some((session, connected, channel)) = connected {
+                        // Client side gateway answers handshake of client with the one of provider
+                        let handshake = match connected {
+                            Ok((addr, frames, handshake)) => {
+                                ws_sessions.connected(session, addr, frames);
+                                handshake
+                            }
+                            Err(handshake) => {
+                                ws_sessions.connect_failed(&session);
+                                error!("ServiceSideGateway: Connect ws service for {} failed with {}", session.1, handshake.status_code);
+                                handshake
+                            }
+                        };
+                        // Sent from its own task, network event loop may be waiting for this loop meanwhile
+                        let mut command_sender = command_sender.clone();
+                        Arbiter::spawn(async move {
+                            let _ = command_sender.send(Command::SendResponse {
+                                response: ProxyResponse::HttpResponse(handshake),
+                                channel,
+                            }).await;
+                        });
+                    }
+                }
+                done = done_receiver.next() => {
+                    if let Some(session) = done {
+                        ws_sessions.done(&session);
                     }
                 }
             }
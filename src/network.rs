@@ -1,52 +1,66 @@
 use std::collections::HashMap;
 use std::error::Error;
-use std::iter;
-use std::time::Duration;
+use std::fmt;
+use std::time::{Duration, Instant};
 
 // use async_std::channel;
-use async_std::io;
-use async_trait::async_trait;
-use bincode;
+use actix_web::web::{Bytes, Data};
 use cargo_contract::Verbosity::Default;
 use futures::channel::mpsc;
+use futures::channel::oneshot;
 use futures::prelude::*;
-use futures::{AsyncWriteExt, StreamExt};
-use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName};
+use futures::stream::LocalBoxStream;
+use futures::StreamExt;
 use libp2p::gossipsub::{GossipsubEvent, IdentTopic as Topic, MessageAuthenticity};
+use libp2p::identity::Keypair;
 use libp2p::kad::record::store::MemoryStore;
-use libp2p::kad::{GetProvidersOk, Kademlia, KademliaEvent, QueryResult};
+use libp2p::kad::{
+    GetClosestPeersOk, GetProvidersOk, Kademlia, KademliaEvent, QueryId, QueryResult,
+};
 use libp2p::request_response::{
-    ProtocolSupport, RequestResponse, RequestResponseCodec, RequestResponseConfig,
+    OutboundFailure, ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig,
     RequestResponseEvent, RequestResponseMessage, ResponseChannel,
 };
 use libp2p::NetworkBehaviour;
 use libp2p::{gossipsub, swarm::SwarmEvent, Multiaddr, PeerId, Swarm};
 use log::{debug, error, info, warn};
-use serde::{Deserialize, Serialize};
 
-use crate::forward_service_models::{HttpProxyResponse, ProxyData, ProxyRequestInfo};
-use crate::forward_service_utils::send_http_request_blocking;
-use crate::service::ApronService;
-use crate::state::{delete, get, set, AppState};
-use crate::{helpers, Opt};
+use crate::announcement::SignedAnnouncement;
+use crate::circuit_breaker::CircuitBreakers;
+use crate::forward_service_models::{BodyChunk, HttpProxyResponse, ProxyRequestInfo};
+use crate::gateway_error::GatewayError;
+use crate::health_check::RemoteHealth;
+use crate::load_balancer::{select_provider, ProviderLease, ProviderSelector};
+use crate::protocol::{
+    registry_page, ProxyCodec, ProxyMessage, ProxyProtocol, ProxyResponse, BODY_CHUNK_SIZE,
+};
+use crate::service::{
+    apply_remote_service, get_active_service, registry_digests, registry_sync_digests,
+    services_newer_than, ApronService,
+};
+use crate::state::{delete, get, set, values, AppState};
+use crate::ws_stream::{WsStream, WsStreamBehaviour, WsStreamEvent};
+use crate::Opt;
 
 #[derive(NetworkBehaviour)]
 #[behaviour(event_process = false, out_event = "ComposedEvent")]
 pub struct ComposedBehaviour {
-    pub request_response: RequestResponse<DataExchangeCodec>,
+    pub request_response: RequestResponse<ProxyCodec>,
     pub gossipsub: gossipsub::Gossipsub,
     pub kademlia: Kademlia<MemoryStore>,
+    pub ws_stream: WsStreamBehaviour,
 }
 
 #[derive(Debug)]
 pub enum ComposedEvent {
-    RequestResponse(RequestResponseEvent<DataExchangeRequest, FileResponse>),
+    RequestResponse(RequestResponseEvent<ProxyMessage, ProxyResponse>),
     Gossipsub(GossipsubEvent),
     Kademlia(KademliaEvent),
+    WsStream(WsStreamEvent),
 }
 
-impl From<RequestResponseEvent<DataExchangeRequest, FileResponse>> for ComposedEvent {
-    fn from(event: RequestResponseEvent<DataExchangeRequest, FileResponse>) -> Self {
+impl From<RequestResponseEvent<ProxyMessage, ProxyResponse>> for ComposedEvent {
+    fn from(event: RequestResponseEvent<ProxyMessage, ProxyResponse>) -> Self {
         ComposedEvent::RequestResponse(event)
     }
 }
@@ -63,29 +77,60 @@ impl From<KademliaEvent> for ComposedEvent {
     }
 }
 
+impl From<WsStreamEvent> for ComposedEvent {
+    fn from(event: WsStreamEvent) -> Self {
+        ComposedEvent::WsStream(event)
+    }
+}
+
 #[derive(Debug)]
 pub enum Command {
     PublishGossip {
         data: Vec<u8>,
     },
+    PublishHealth {
+        data: Vec<u8>,
+    },
+    // Open websocket session on service side gateway, handshake response of provider is returned with sender
     SendRequest {
+        peer: PeerId,
+        info: ProxyRequestInfo,
+        sender: oneshot::Sender<Result<HttpProxyResponse, GatewayError>>,
+    },
+    // Send http request to service side gateway, the response is returned with sender
+    SendHttpRequest {
+        peer: PeerId,
+        info: ProxyRequestInfo,
+        sender: oneshot::Sender<HttpResponseResult>,
+    },
+    // Send piece of body, sender is notified once the peer acknowledged it
+    SendBodyChunk {
+        peer: PeerId,
+        chunk: BodyChunk,
+        sender: oneshot::Sender<Result<(), String>>,
+    },
+    // Tell peer the request is abandoned by client
+    SendClose {
         peer: PeerId,
         request_id: String,
-        data: Vec<u8>,
     },
-    SendProxyDataFromService {
+    // Tell peer the request failed
+    SendError {
         peer: PeerId,
         request_id: String,
-        data: Vec<u8>,
+        error: GatewayError,
     },
     SendResponse {
-        data: Vec<u8>,
-        channel: ResponseChannel<FileResponse>,
+        response: ProxyResponse,
+        channel: ResponseChannel<ProxyResponse>,
     },
 
-    SendProxyData {
+    // Open stream of websocket session accepted by service side gateway, frames of
+    // the session are relayed over it in both directions
+    OpenWsStream {
         peer: PeerId,
-        data: Vec<u8>,
+        request_id: String,
+        sender: oneshot::Sender<Result<WsStream, String>>,
     },
 
     Dial {
@@ -93,6 +138,13 @@ pub enum Command {
         peer_addr: Multiaddr,
     },
 
+    // Make sure a connection to the peer exists, dial it (with the help of kademlia
+    // if no address is known) and report the result with sender.
+    EnsureConnected {
+        peer: PeerId,
+        sender: oneshot::Sender<Result<(), String>>,
+    },
+
     AddService {
         args: Vec<String>,
     },
@@ -105,23 +157,306 @@ pub enum Command {
 #[derive(Debug)]
 pub enum Event {
     ProxyRequestToMainLoop {
-        ws_base: String,
+        // Service of the request, which decides timeouts and header rules
+        service: ApronService,
+        provider: ProviderLease,
+        info: ProxyRequestInfo,
+        remote_peer_id: PeerId,
+        // Handshake response of provider is replied once connected
+        channel: ResponseChannel<ProxyResponse>,
+    },
+
+    HttpRequestToMainLoop {
+        // Service of the request, which decides timeouts and retries
+        service: ApronService,
+        provider: ProviderLease,
         info: ProxyRequestInfo,
         remote_peer_id: PeerId,
+        // Rest of request body, if it is sent in chunks
+        body: Option<BodyReceiver>,
+        // Fired if client side gateway closed the request
+        cancel: oneshot::Receiver<()>,
+        channel: ResponseChannel<ProxyResponse>,
     },
 
-    ProxyDataFromClient {
-        data: ProxyData,
+    // Stream of websocket session opened by client side gateway
+    WsStreamFromClient {
+        request_id: String,
+        remote_peer_id: PeerId,
+        stream: WsStream,
     },
 
-    ProxyDataFromService {
-        data: ProxyData,
+    // Session is abandoned by client side gateway
+    SessionClosed {
+        request_id: String,
+        remote_peer_id: PeerId,
     },
 }
 
-pub async fn new(secret_key_seed: Option<u8>) -> Result<Swarm<ComposedBehaviour>, Box<dyn Error>> {
-    // Create a public/private key pair, either random or based on a seed.
-    let (local_key, local_peer_id) = helpers::generate_peer_id_from_seed(secret_key_seed);
+/// Piece of body received from peer. The peer sends the next piece after `ack` is replied,
+/// which is done by consumer of the body once the piece is taken.
+#[derive(Debug)]
+pub struct BodyPiece {
+    pub data: Result<Vec<u8>, String>,
+    pub ack: Option<ResponseChannel<ProxyResponse>>,
+}
+
+pub type BodyReceiver = mpsc::Receiver<BodyPiece>;
+
+/// Response of service side gateway, with receiver of the rest of body if it is sent in chunks.
+pub type HttpResponseResult = Result<(HttpProxyResponse, Option<BodyReceiver>), GatewayError>;
+
+// First chunk of a body may arrive before the request or response announcing the body,
+// it is kept for a while, and only a few of them from each peer.
+const EARLY_CHUNK_TTL: Duration = Duration::from_secs(10);
+const MAX_EARLY_CHUNKS_PER_PEER: usize = 16;
+
+// Body received in chunks from a peer, created once the request or response
+// carrying head announces the rest of body.
+struct BodyStream {
+    // Dropped after the last chunk received, which ends the body
+    sender: Option<mpsc::Sender<BodyPiece>>,
+    next_seq: u64,
+}
+
+impl BodyStream {
+    fn new() -> (Self, BodyReceiver) {
+        // Only one piece is unacknowledged at a time, the extra slot is for error
+        let (sender, receiver) = mpsc::channel(1);
+        let stream = BodyStream {
+            sender: Some(sender),
+            next_seq: 0,
+        };
+        (stream, receiver)
+    }
+
+    // Pass chunk to consumer, ack is returned back if chunk is rejected
+    fn push(
+        &mut self,
+        chunk: BodyChunk,
+        ack: ResponseChannel<ProxyResponse>,
+    ) -> Result<(), (String, ResponseChannel<ProxyResponse>)> {
+        if chunk.seq != self.next_seq {
+            return Err((
+                format!("Expect body chunk {}, got {}", self.next_seq, chunk.seq),
+                ack,
+            ));
+        }
+        let sender = match self.sender.as_mut() {
+            Some(sender) => sender,
+            None => return Err((String::from("Body is already finished"), ack)),
+        };
+
+        let is_last = chunk.is_last;
+        let piece = BodyPiece {
+            data: Ok(chunk.data),
+            ack: Some(ack),
+        };
+        if let Err(e) = sender.try_send(piece) {
+            let reason = if e.is_disconnected() {
+                "Session is closed"
+            } else {
+                "Body chunk sent before previous one acknowledged"
+            };
+            let ack = e.into_inner().ack.expect("Ack to be set.");
+            return Err((String::from(reason), ack));
+        }
+
+        self.next_seq += 1;
+        if is_last {
+            self.sender = None;
+        }
+        Ok(())
+    }
+
+    // End body with error, so consumer won't take it as complete
+    fn fail(&mut self, message: String) {
+        if let Some(mut sender) = self.sender.take() {
+            let _ = sender.try_send(BodyPiece {
+                data: Err(message),
+                ack: None,
+            });
+        }
+    }
+
+    // Body is finished, or its consumer is gone
+    fn is_done(&self) -> bool {
+        self.sender
+            .as_ref()
+            .map_or(true, |sender| sender.is_closed())
+    }
+}
+
+// Bodies being received in chunks, keyed by sending peer and request id.
+// Chunks are only accepted for bodies announced by this gateway's peer.
+#[derive(Default)]
+struct BodyStreams {
+    streams: HashMap<(PeerId, String), BodyStream>,
+    // First chunks of bodies not announced yet, with time received
+    early: HashMap<(PeerId, String), (BodyChunk, ResponseChannel<ProxyResponse>, Instant)>,
+}
+
+impl BodyStreams {
+    // Start receiving body announced for the request, returns None if it is already being received
+    fn open(&mut self, key: (PeerId, String)) -> Option<BodyReceiver> {
+        if self.streams.contains_key(&key) {
+            return None;
+        }
+        let (mut stream, receiver) = BodyStream::new();
+        if let Some((chunk, ack, _)) = self.early.remove(&key) {
+            // Ack is dropped if the chunk is rejected, which fails it on the peer
+            if let Err((e, _)) = stream.push(chunk, ack) {
+                warn!(
+                    "[libp2p] Reject body chunk of {} from {}: {}",
+                    key.1, key.0, e
+                );
+            }
+        }
+        if !stream.is_done() {
+            self.streams.insert(key, stream);
+        }
+        Some(receiver)
+    }
+
+    // Pass chunk to the body it belongs to, ack is returned back if chunk is rejected
+    fn push(
+        &mut self,
+        key: (PeerId, String),
+        chunk: BodyChunk,
+        ack: ResponseChannel<ProxyResponse>,
+    ) -> Result<(), (String, ResponseChannel<ProxyResponse>)> {
+        let stream = match self.streams.get_mut(&key) {
+            Some(stream) => stream,
+            None => return self.keep_early(key, chunk, ack),
+        };
+        let pushed = stream.push(chunk, ack);
+        if pushed.is_err() || stream.is_done() {
+            self.streams.remove(&key);
+        }
+        pushed
+    }
+
+    // Keep the first chunk of body until it is announced
+    fn keep_early(
+        &mut self,
+        key: (PeerId, String),
+        chunk: BodyChunk,
+        ack: ResponseChannel<ProxyResponse>,
+    ) -> Result<(), (String, ResponseChannel<ProxyResponse>)> {
+        // Chunks expired are dropped along with their ack, which fails them on the peer
+        self.early
+            .retain(|_, (_, _, received_at)| received_at.elapsed() < EARLY_CHUNK_TTL);
+        if chunk.seq != 0 || self.early.contains_key(&key) {
+            return Err((format!("Body of {} is not announced", key.1), ack));
+        }
+        let kept = self.early.keys().filter(|(peer, _)| *peer == key.0).count();
+        if kept >= MAX_EARLY_CHUNKS_PER_PEER {
+            return Err((String::from("Too many bodies not announced"), ack));
+        }
+        self.early.insert(key, (chunk, ack, Instant::now()));
+        Ok(())
+    }
+
+    // Stop receiving body of the request, returns it if it is still being received
+    fn remove(&mut self, key: &(PeerId, String)) -> Option<BodyStream> {
+        self.early.remove(key);
+        self.streams.remove(key)
+    }
+}
+
+/// Turn body received from peer into stream of data. Each piece is acknowledged
+/// while taken from the stream, so the peer won't send faster than body is consumed.
+pub fn body_stream(
+    receiver: BodyReceiver,
+    command_sender: mpsc::Sender<Command>,
+) -> LocalBoxStream<'static, Result<Bytes, String>> {
+    receiver
+        .then(move |piece| {
+            let mut command_sender = command_sender.clone();
+            async move {
+                if let Some(channel) = piece.ack {
+                    let _ = command_sender
+                        .send(Command::SendResponse {
+                            response: ProxyResponse::Ack,
+                            channel,
+                        })
+                        .await;
+                }
+                piece.data.map(Bytes::from)
+            }
+        })
+        .boxed_local()
+}
+
+/// Send body to peer in chunks, the next chunk is read from body after previous one
+/// is acknowledged. The peer is told if the body breaks in the middle.
+pub async fn send_body_chunks<S, E>(
+    mut command_sender: mpsc::Sender<Command>,
+    peer: PeerId,
+    request_id: String,
+    mut body: S,
+) -> Result<(), String>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: fmt::Display,
+{
+    let mut seq = 0;
+    loop {
+        let (data, is_last) = match body.next().await {
+            Some(Ok(data)) => (data, false),
+            Some(Err(e)) => {
+                let message = format!("Body is broken: {}", e);
+                command_sender
+                    .send(Command::SendError {
+                        peer,
+                        request_id,
+                        error: GatewayError::BadGateway(message.clone()),
+                    })
+                    .await
+                    .map_err(|e| e.to_string())?;
+                return Err(message);
+            }
+            None => (Bytes::new(), true),
+        };
+
+        // Large pieces are split to fit in messages, end of body is marked with an empty chunk
+        let mut pieces: Vec<Vec<u8>> = data
+            .chunks(BODY_CHUNK_SIZE)
+            .map(|piece| piece.to_vec())
+            .collect();
+        if is_last {
+            pieces.push(Vec::new());
+        }
+
+        for piece in pieces {
+            let (sender, receiver) = oneshot::channel();
+            command_sender
+                .send(Command::SendBodyChunk {
+                    peer,
+                    chunk: BodyChunk {
+                        request_id: request_id.clone(),
+                        seq,
+                        data: piece,
+                        is_last,
+                    },
+                    sender,
+                })
+                .await
+                .map_err(|e| e.to_string())?;
+            receiver
+                .await
+                .map_err(|_| String::from("Network event loop is stopped"))??;
+            seq += 1;
+        }
+
+        if is_last {
+            return Ok(());
+        }
+    }
+}
+
+pub async fn new(local_key: Keypair) -> Result<Swarm<ComposedBehaviour>, Box<dyn Error>> {
+    let local_peer_id = local_key.public().into_peer_id();
 
     info!("Local peer id: {:?}", local_peer_id);
 
@@ -147,12 +482,16 @@ pub async fn new(secret_key_seed: Option<u8>) -> Result<Swarm<ComposedBehaviour>
         gossipsub.subscribe(&topic).unwrap();
 
         let mut cfg = RequestResponseConfig::default();
-        cfg.set_request_timeout(Duration::from_secs(60));
+        // Long polling and streaming requests may wait for upstream response this long
+        cfg.set_request_timeout(Duration::from_secs(300));
         cfg.set_connection_keep_alive(Duration::from_secs(60));
 
+        // Peers negotiate the newest protocol version supported by both sides
         let request_response = RequestResponse::new(
-            DataExchangeCodec(),
-            iter::once((DataExchangeProtocol(), ProtocolSupport::Full)),
+            ProxyCodec(),
+            ProxyProtocol::supported()
+                .into_iter()
+                .map(|protocol| (protocol, ProtocolSupport::Full)),
             cfg,
         );
         let kademlia = Kademlia::new(local_peer_id, MemoryStore::new(local_peer_id));
@@ -164,6 +503,7 @@ pub async fn new(secret_key_seed: Option<u8>) -> Result<Swarm<ComposedBehaviour>
                 request_response,
                 gossipsub,
                 kademlia,
+                ws_stream: WsStreamBehaviour::default(),
             },
             local_peer_id,
         )
@@ -172,6 +512,7 @@ pub async fn new(secret_key_seed: Option<u8>) -> Result<Swarm<ComposedBehaviour>
     Ok(swarm)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn network_event_loop(
     mut swarm: Swarm<ComposedBehaviour>,
     receiver: mpsc::Receiver<Command>,
@@ -180,14 +521,72 @@ pub async fn network_event_loop(
     req_id_client_session_mapping: AppState<mpsc::Sender<HttpProxyResponse>>,
     opt: Opt,
     service_data: AppState<ApronService>,
+    provider_selector: Data<ProviderSelector>,
+    circuit_breakers: Data<CircuitBreakers>,
+    remote_health: Data<RemoteHealth>,
+    announcements: AppState<SignedAnnouncement>,
+    local_key: Keypair,
 ) {
     // Create a Gossipsub topic
     let topic = Topic::new("apron-test-net");
+    // Topic for provider health status, separated from service announcements
+    let health_topic = Topic::new("apron-test-net-health");
     info!("network_event_loop started");
     swarm.behaviour_mut().gossipsub.subscribe(&topic).unwrap();
+    swarm
+        .behaviour_mut()
+        .gossipsub
+        .subscribe(&health_topic)
+        .unwrap();
 
     let mut receiver = receiver.fuse();
 
+    // Services loaded from registry storage are announced again once the first peer joins the topic
+    let mut local_services_announced = false;
+    // Latest signed announcement of services is kept in announcements, and forwarded to peers
+    // while syncing registry. Local services are signed again, in case the saved ones are lost.
+    let local_peer_id = Some(swarm.local_peer_id().to_base58());
+    for service in values(service_data.clone()).unwrap() {
+        if service.peer_id != local_peer_id {
+            continue;
+        }
+        match SignedAnnouncement::sign(&local_key, serde_json::to_vec(&service).unwrap()) {
+            Ok(announcement) => {
+                set(announcements.clone(), service.id, announcement);
+            }
+            Err(e) => error!("[libp2p] Sign service {} failed: {}", service.id, e),
+        }
+    }
+
+    // Senders waiting for a connection to the peer to be established
+    let mut pending_dials: HashMap<PeerId, Vec<oneshot::Sender<Result<(), String>>>> =
+        HashMap::new();
+    // Kademlia lookups started for peers without known address
+    let mut pending_peer_lookups: HashMap<QueryId, PeerId> = HashMap::new();
+    // Registry sync requests waiting for services from peer, with versions of services received so far
+    let mut pending_registry_syncs: HashMap<RequestId, (PeerId, HashMap<String, u64>)> =
+        HashMap::new();
+    // Init proxy requests of websocket sessions waiting for acknowledgement, valued by client side request id
+    let mut pending_proxy_requests: HashMap<
+        RequestId,
+        (
+            String,
+            oneshot::Sender<Result<HttpProxyResponse, GatewayError>>,
+        ),
+    > = HashMap::new();
+    // Http requests waiting for response of service side gateway, with client side request id
+    let mut pending_http_requests: HashMap<
+        RequestId,
+        (String, oneshot::Sender<HttpResponseResult>),
+    > = HashMap::new();
+    // Body chunks waiting for acknowledgement
+    let mut pending_chunk_acks: HashMap<RequestId, oneshot::Sender<Result<(), String>>> =
+        HashMap::new();
+    // Bodies being received in chunks
+    let mut body_streams = BodyStreams::default();
+    // Upstream requests being processed in main loop, keyed by client peer and request id
+    let mut upstream_cancels: HashMap<(PeerId, String), oneshot::Sender<()>> = HashMap::new();
+
 /// SBP M2 What if events are received faster than they can be processed?
     loop {
         let share_data = data.clone();
@@ -199,15 +598,81 @@ pub async fn network_event_loop(
                     SwarmEvent::NewListenAddr { address, .. } => {
                         info!("Listening on {}", address);
                     }
-                    SwarmEvent::ConnectionEstablished { peer_id, endpoint,.. } => {
+                    SwarmEvent::ConnectionEstablished { peer_id, endpoint, num_established, .. } => {
                         warn!("Connected to {} on {}", peer_id, endpoint.get_remote_address());
                         let remote_address = endpoint.get_remote_address();
                         swarm.behaviour_mut().kademlia.add_address(&peer_id, remote_address.clone());
+                        if num_established.get() == 1 {
+                            // Pull services missing or differing in local registry from the new peer
+                            let digests = registry_digests(service_data.clone());
+                            info!("[libp2p] Sync registry with {}, local services: {}", peer_id, digests.len());
+                            let request_id = swarm.behaviour_mut().request_response.send_request(&peer_id, ProxyMessage::RegistrySync(digests));
+                            pending_registry_syncs.insert(request_id, (peer_id, HashMap::new()));
+                        }
+                        if let Some(senders) = pending_dials.remove(&peer_id) {
+                            for sender in senders {
+                                let _ = sender.send(Ok(()));
+                            }
+                        }
+                    }
+                    SwarmEvent::UnreachableAddr { peer_id, address, error, attempts_remaining } => {
+                        warn!("Failed to reach {} on {}: {:?}", peer_id, address, error);
+                        if attempts_remaining == 0 {
+                            if let Some(senders) = pending_dials.remove(&peer_id) {
+                                for sender in senders {
+                                    let _ = sender.send(Err(format!("Peer {} is unreachable: {:?}", peer_id, error)));
+                                }
+                            }
+                        }
                     }
                     SwarmEvent::ConnectionClosed { peer_id, ..} => {
                         warn!("Disconnected from {}", peer_id);
                         swarm.behaviour_mut().kademlia.remove_peer(&peer_id);
                     }
+                    SwarmEvent::Behaviour(ComposedEvent::Gossipsub(
+                        GossipsubEvent::Subscribed { peer_id, topic: subscribed_topic }
+                    )) if subscribed_topic == topic.hash() && !local_services_announced => {
+                        local_services_announced = true;
+                        for service in values(service_data.clone()).unwrap() {
+                            if service.peer_id != local_peer_id {
+                                continue;
+                            }
+                            info!("[libp2p] Announce local service {} to {}", service.id, peer_id);
+                            if let Some(announcement) = get(announcements.clone(), service.id.clone()) {
+                                if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), serde_json::to_vec(&announcement).unwrap()) {
+                                    warn!("[libp2p] Announce local service {} failed: {:?}", service.id, e);
+                                }
+                            }
+                        }
+                    }
+                    SwarmEvent::Behaviour(ComposedEvent::Gossipsub(
+                     GossipsubEvent::Message {
+                        propagation_source: peer_id,
+                        message_id: _,
+                        message,
+                    })) if message.topic == health_topic.hash() => {
+                        let opened = serde_json::from_slice::<SignedAnnouncement>(&message.data)
+                            .map_err(|e| e.to_string())
+                            .and_then(|announcement| announcement.open_health())
+                            .and_then(|(signer, health)| {
+                                if message.source != Some(signer) {
+                                    return Err(format!("published by {:?}, signed by {}", message.source, signer));
+                                }
+                                // Only the gateway owning the service checks its providers
+                                match get(service_data.clone(), health.service_id.clone()) {
+                                    Some(service) if service.peer_id == Some(signer.to_base58()) => Ok(health),
+                                    Some(service) => Err(format!("service {} owned by {:?}, signed by {}", health.service_id, service.peer_id, signer)),
+                                    None => Err(format!("service {} is unknown", health.service_id)),
+                                }
+                            });
+                        match opened {
+                            Ok(health) => {
+                                info!("[libp2p] Receive provider health from {}: {:?}", peer_id, health);
+                                remote_health.update(health);
+                            }
+                            Err(e) => warn!("[libp2p] Drop provider health message from {}: {}", peer_id, e),
+                        }
+                    }
                     SwarmEvent::Behaviour(ComposedEvent::Gossipsub(
                      GossipsubEvent::Message {
                         propagation_source: peer_id,
@@ -216,32 +681,34 @@ pub async fn network_event_loop(
                     })) => {
                         // update local http gateway data.
                         println!("[libp2p] Recevie new message from remote: {}", peer_id);
-                        let value = String::from_utf8_lossy(&message.data).to_string();
-                        let new_service: ApronService = serde_json::from_str(&value).unwrap();
-                        let key = new_service.id.clone();
-                        let service = get(share_data.clone(), key.clone());
-                        match service {
-                            Some(_service) => {
-                                match new_service.is_deleted {
-                                    Some(is_deleted) => {
+                        // The announcement must be signed by the gateway published it, which is also the owner of service
+                        let opened = serde_json::from_slice::<SignedAnnouncement>(&message.data)
+                            .map_err(|e| e.to_string())
+                            .and_then(|announcement| {
+                                let (signer, service) = announcement.open_service()?;
+                                if message.source == Some(signer) {
+                                    Ok((announcement, service))
+                                } else {
+                                    Err(format!("published by {:?}, signed by {}", message.source, signer))
+                                }
+                            });
+                        match opened {
+                            Ok((announcement, new_service)) => {
+                                let key = new_service.id.clone();
+                                let is_deleted = !new_service.is_active();
+                                match apply_remote_service(share_data, new_service) {
+                                    Ok(()) => {
                                         if is_deleted {
-                                            println!("[libp2p] Recevie new message to delete service: {}", key.clone());
-                                            delete(share_data, key.clone());
-                                        }else{
-                                            println!("[libp2p] Recevie new message to update service: {}", key.clone());
-                                            set(share_data, key.clone(), new_service);
+                                            println!("[libp2p] Recevie new message to delete service: {}", key);
+                                        } else {
+                                            println!("[libp2p] Recevie new message to update service: {}", key);
                                         }
+                                        set(announcements.clone(), key, announcement);
                                     }
-                                    None => {
-                                        println!("[libp2p] Recevie new message to update service: {}", key.clone());
-                                        set(share_data, key.clone(), new_service);
-                                    }
+                                    Err(e) => warn!("[libp2p] Reject service message from {}: {}", peer_id, e),
                                 }
                             }
-                            None => {
-                                println!("[libp2p] Recevie new message to add new service: {}", key.clone());
-                                set(share_data, key.clone(), new_service);
-                            }
+                            Err(e) => warn!("[libp2p] Drop service message from {}: {}", peer_id, e),
                         }
                     }
 
@@ -249,107 +716,262 @@ pub async fn network_event_loop(
                         RequestResponseEvent::Message { peer, message },
                     )) => match message {
                         RequestResponseMessage::Request { request, channel, .. } => {
-                            // request is DataExchangeRequest object
                             info!("[libp2p] receive request message: {:?}, channel: {:?}", request, channel);
                             info!("Request from Peer id {:?}", peer);
 
-                            match request.schema {
+                            // Taken by requests which are replied once processed in main loop
+                            let mut channel = Some(channel);
+                            let response = match request {
                                 // Init connection request sent from Client
-                                0 => {
-                                    let proxy_request_info: ProxyRequestInfo = bincode::deserialize(&request.data).unwrap();
+                                ProxyMessage::InitProxy(proxy_request_info) => {
                                     info!("ProxyRequestInfo is {:?}", proxy_request_info);
 
                                     let client_side_req_id = proxy_request_info.clone().request_id;
                                     let service_id = proxy_request_info.clone().service_id;
                                     debug!("All service data in remote: {:?}", service_data.clone());
-                                    let service = get(service_data.clone(), service_id.clone()).unwrap();
-
-                                    if proxy_request_info.clone().is_websocket {
-                                        // Running on service side gateway, after receiving websocket request,
-                                        // forward the request directly to main loop since the event handler
-                                        // can't process async tasks well.
-                                        info!("Forwarding ws request to main loop");
-
-                                        event_sender.send(Event::ProxyRequestToMainLoop{
-                                            ws_base: service.get_ws_provider().unwrap(),
-                                            info: proxy_request_info.clone(),
-                                            remote_peer_id: peer,
-                                        }).await.expect("Event receiver not to be dropped.");
-
-                                        swarm.behaviour_mut()
-                                                    .request_response
-                                                    .send_response(channel, FileResponse(vec![1,2,3])).unwrap();
-                                    } else {
-                                        let resp = send_http_request_blocking(proxy_request_info.clone(), service.get_http_provider()).unwrap();
-
-                                        swarm.behaviour_mut()
-                                                    .request_response
-                                                    .send_response(channel, FileResponse(vec![1,2,3])).unwrap();
-
-                                        // Send resp to client side gateway
-                                        swarm.behaviour_mut()
-                                                .request_response
-                                                .send_request(&peer, DataExchangeRequest{schema: 3, data:bincode::serialize(&resp).unwrap()});
+                                    let response = match get_active_service(service_data.clone(), service_id.clone()) {
+                                        None => ProxyResponse::Error(GatewayError::NotFound(format!("Service {} not found", service_id))),
+                                        Some(service) if proxy_request_info.is_websocket => {
+                                            // Running on service side gateway, after receiving websocket request,
+                                            // forward the request directly to main loop since the event handler
+                                            // can't process async tasks well.
+                                            info!("Forwarding ws request to main loop");
+
+                                            match select_provider(provider_selector.clone(), &circuit_breakers.providers, &service, "ws") {
+                                                Some(provider) => {
+                                                    event_sender.send(Event::ProxyRequestToMainLoop{
+                                                        service: service.clone(),
+                                                        provider,
+                                                        info: proxy_request_info.clone(),
+                                                        remote_peer_id: peer,
+                                                        channel: channel.take().expect("Channel not to be taken."),
+                                                    }).await.expect("Event receiver not to be dropped.");
+                                                    ProxyResponse::Ack
+                                                }
+                                                None => {
+                                                    error!("No healthy ws provider for service {}", service_id);
+                                                    ProxyResponse::Error(GatewayError::ServiceUnavailable(format!("No healthy ws provider for service {}", service_id)))
+                                                }
+                                            }
+                                        }
+                                        Some(service) => match select_provider(provider_selector.clone(), &circuit_breakers.providers, &service, "http") {
+                                            Some(provider) => {
+                                                let body_key = (peer, client_side_req_id.clone());
+                                                let body = if proxy_request_info.has_more_body {
+                                                    body_streams.open(body_key)
+                                                } else {
+                                                    None
+                                                };
+                                                if proxy_request_info.has_more_body && body.is_none() {
+                                                    ProxyResponse::Error(GatewayError::BadRequest(format!("Request {} is already received", client_side_req_id)))
+                                                } else {
+                                                    // Forget requests already finished
+                                                    upstream_cancels.retain(|_, cancel| !cancel.is_canceled());
+                                                    let (cancel_sender, cancel) = oneshot::channel();
+                                                    upstream_cancels.insert((peer, client_side_req_id.clone()), cancel_sender);
+
+                                                    // Upstream request is sent from main loop, so slow providers won't block
+                                                    // the event loop, response is replied on the channel from there.
+                                                    event_sender.send(Event::HttpRequestToMainLoop{
+                                                        service: service.clone(),
+                                                        provider,
+                                                        info: proxy_request_info.clone(),
+                                                        remote_peer_id: peer,
+                                                        body,
+                                                        cancel,
+                                                        channel: channel.take().expect("Channel not to be taken."),
+                                                    }).await.expect("Event receiver not to be dropped.");
+                                                    ProxyResponse::Ack
+                                                }
+                                            }
+                                            None => {
+                                                error!("No healthy http provider for service {}", service_id);
+                                                ProxyResponse::HttpResponse(HttpProxyResponse::with_error(
+                                                    client_side_req_id.clone(),
+                                                    &GatewayError::ServiceUnavailable(format!("No healthy http provider for service {}", service_id)),
+                                                ))
+                                            }
+                                        },
+                                    };
+                                    // Drop body chunks of request not passed to provider
+                                    if proxy_request_info.has_more_body && channel.is_some() {
+                                        body_streams.remove(&(peer, client_side_req_id.clone()));
                                     }
+                                    response
                                 }
-                                1 => {
-                                    // Received proxy data from ClientSideGateway, and forward to service
-                                    let proxy_data: ProxyData = bincode::deserialize(&request.data).unwrap();
-                                    info!("Received proxy data request: {:?}", proxy_data);
-
-                                    swarm.behaviour_mut()
-                                                .request_response
-                                                .send_response(channel, FileResponse(vec![1,2,3])).unwrap();
-
-                                    event_sender.send(Event::ProxyDataFromClient{
-                                        data: proxy_data,
-                                    }).await.expect("Event receiver not to be dropped.");
+                                ProxyMessage::RegistrySync(digests) => {
+                                    // Registry digests sent from a peer just connected,
+                                    // reply with services it is missing or has different version of.
+                                    // Only entries with signed announcement can be verified by the peer
+                                    let services: Vec<SignedAnnouncement> = services_newer_than(service_data.clone(), &digests)
+                                        .iter()
+                                        .filter_map(|service| get(announcements.clone(), service.id.clone()))
+                                        .collect();
+                                    // Peer asks again for services not fitting in one response
+                                    let total = services.len();
+                                    let services = registry_page(services);
+                                    info!("[libp2p] Send {} of {} services to {} for registry sync", services.len(), total, peer);
+                                    ProxyResponse::RegistryEntries(services)
                                 }
-                                2 => {
-                                    info!("Received ws data from service: {:?}", request);
-                                    swarm.behaviour_mut()
-                                            .request_response
-                                            .send_response(channel, FileResponse(vec![1,2,3])).unwrap();
-
-                                    info!("Send response back to client");
-                                    let proxy_data: ProxyData = bincode::deserialize(&request.data).unwrap();
-                                    let mut sender = get(req_id_client_session_mapping.clone(), proxy_data.clone().request_id).unwrap();
-                                    sender.send(HttpProxyResponse {
-                                        request_id: proxy_data.clone().request_id,
-                                        status_code: 200,
-                                        is_websocket_resp: true,
-                                        headers: HashMap::new(),
-                                        body: proxy_data.clone().data,
+                                ProxyMessage::BodyChunk(chunk) => {
+                                    let key = (peer, chunk.request_id.clone());
+                                    // Acknowledged by consumer of the body once the chunk is taken
+                                    match body_streams.push(key.clone(), chunk, channel.take().expect("Channel not to be taken.")) {
+                                        Ok(()) => ProxyResponse::Ack,
+                                        Err((e, ack)) => {
+                                            warn!("[libp2p] Reject body chunk of {} from {}: {}", key.1, peer, e);
+                                            channel = Some(ack);
+                                            ProxyResponse::Error(GatewayError::BadRequest(e))
+                                        }
+                                    }
+                                }
+                                ProxyMessage::Error { request_id, error } => {
+                                    warn!("[libp2p] Request {} failed on {}: {}", request_id, peer, error);
+                                    if let Some(mut stream) = body_streams.remove(&(peer, request_id.clone())) {
+                                        stream.fail(error.to_string());
+                                    }
+                                    deliver_to_client_session(
+                                        req_id_client_session_mapping.clone(),
+                                        HttpProxyResponse::with_error(request_id, &error),
+                                    )
+                                }
+                                ProxyMessage::Close { request_id } => {
+                                    info!("[libp2p] Session {} closed by {}", request_id, peer);
+                                    // Client is gone, stop upstream request and drop body still being uploaded
+                                    let key = (peer, request_id.clone());
+                                    if let Some(cancel) = upstream_cancels.remove(&key) {
+                                        let _ = cancel.send(());
+                                    }
+                                    body_streams.remove(&key);
+                                    delete(req_id_client_session_mapping.clone(), request_id.clone());
+                                    // Websocket session to provider is closed as well, if any
+                                    event_sender.send(Event::SessionClosed {
+                                        request_id,
+                                        remote_peer_id: peer,
                                     }).await.expect("Event receiver not to be dropped.");
+                                    ProxyResponse::Ack
                                 }
-                                3 => {
-                                    info!("Received http data from service: {:?}", request);
-                                    swarm.behaviour_mut()
-                                            .request_response
-                                            .send_response(channel, FileResponse(vec![1,2,3])).unwrap();
-                                    let resp: HttpProxyResponse = bincode::deserialize(&request.data).unwrap();
-                                    let mut sender = get(req_id_client_session_mapping.clone(), resp.clone().request_id).unwrap();
-                                    sender.send(resp).await.expect("Event receiver not to be dropped.");
+                                ProxyMessage::Unsupported(e) => {
+                                    warn!("[libp2p] Unsupported message from {}: {}", peer, e);
+                                    ProxyResponse::Unsupported
+                                }
+                            };
+
+                            if let Some(channel) = channel {
+                                if swarm.behaviour_mut().request_response.send_response(channel, response).is_err() {
+                                    warn!("[libp2p] Response to {} dropped, connection is closed", peer);
                                 }
-                                _ => { error!("Unknown data schema: {:?}", request.schema)}
                             }
                         }
 
                         RequestResponseMessage::Response { request_id, response, } => {
                             info!("[libp2p] receive response message: {:?}, req_id: {:?}", response, request_id);
+                            if let Some((sync_peer, mut received)) = pending_registry_syncs.remove(&request_id) {
+                                match response {
+                                    ProxyResponse::RegistryEntries(services) => {
+                                        let mut has_new = false;
+                                        for announcement in services {
+                                            let applied = announcement.open_service().and_then(|(_, service)| {
+                                                let key = service.id.clone();
+                                                let version = service.version.unwrap_or(0);
+                                                if received.get(&key).map_or(true, |received| *received < version) {
+                                                    received.insert(key.clone(), version);
+                                                    has_new = true;
+                                                }
+                                                apply_remote_service(service_data.clone(), service).map(|_| key)
+                                            });
+                                            match applied {
+                                                Ok(key) => {
+                                                    info!("[libp2p] Service {} synced from {}", key, sync_peer);
+                                                    set(announcements.clone(), key, announcement);
+                                                }
+                                                Err(e) => warn!("[libp2p] Reject service synced from {}: {}", sync_peer, e),
+                                            }
+                                        }
+                                        // Response may be one page of the services, ask for the rest until no new one is sent
+                                        if has_new {
+                                            let digests = registry_sync_digests(service_data.clone(), &received);
+                                            let request_id = swarm.behaviour_mut().request_response.send_request(&sync_peer, ProxyMessage::RegistrySync(digests));
+                                            pending_registry_syncs.insert(request_id, (sync_peer, received));
+                                        }
+                                    }
+                                    other => warn!("[libp2p] Invalid registry sync response from {}: {:?}", sync_peer, other),
+                                }
+                            } else if let Some(sender) = pending_chunk_acks.remove(&request_id) {
+                                let result = match response {
+                                    ProxyResponse::Ack => Ok(()),
+                                    ProxyResponse::Error(e) => Err(e.to_string()),
+                                    other => Err(format!("Unexpected response for chunk: {:?}", other)),
+                                };
+                                let _ = sender.send(result);
+                            } else if let Some((client_request_id, sender)) = pending_http_requests.remove(&request_id) {
+                                let body_key = (peer, client_request_id);
+                                let result = match response {
+                                    ProxyResponse::HttpResponse(resp) if resp.has_more_body => {
+                                        match body_streams.open(body_key) {
+                                            Some(body) => Ok((resp, Some(body))),
+                                            None => Err(GatewayError::BadGateway(String::from("Body of response is already received"))),
+                                        }
+                                    }
+                                    ProxyResponse::HttpResponse(resp) => Ok((resp, None)),
+                                    ProxyResponse::Error(e) => {
+                                        body_streams.remove(&body_key);
+                                        Err(e)
+                                    }
+                                    other => Err(GatewayError::BadGateway(format!("Unexpected response from service gateway: {:?}", other))),
+                                };
+                                let _ = sender.send(result);
+                            } else if let Some((client_request_id, sender)) = pending_proxy_requests.remove(&request_id) {
+                                // Client session is started only if provider accepts the handshake
+                                let result = match response {
+                                    ProxyResponse::HttpResponse(resp) => Ok(resp),
+                                    ProxyResponse::Error(e) => Err(e),
+                                    ProxyResponse::Unsupported => Err(GatewayError::BadGateway(String::from("Request is not supported by service gateway"))),
+                                    other => Err(GatewayError::BadGateway(format!("Unexpected response from service gateway: {:?}", other))),
+                                };
+                                if let Err(e) = &result {
+                                    warn!("[libp2p] Request {} rejected by {}: {}", client_request_id, peer, e);
+                                }
+                                let _ = sender.send(result);
+                            } else if matches!(response, ProxyResponse::Error(_) | ProxyResponse::Unsupported) {
+                                warn!("[libp2p] Message {:?} rejected by {}: {:?}", request_id, peer, response);
+                            }
                         }
                     }
 
                     SwarmEvent::Behaviour(ComposedEvent::RequestResponse(
                         RequestResponseEvent::OutboundFailure {
-                            request_id, error, ..
+                            request_id, error, peer,
                         },
-                    )) =>{}
+                    )) => {
+                        warn!("[libp2p] Request {:?} to {} failed: {:?}", request_id, peer, error);
+                        pending_registry_syncs.remove(&request_id);
+                        if let Some((client_request_id, sender)) = pending_http_requests.remove(&request_id) {
+                            body_streams.remove(&(peer, client_request_id));
+                            let _ = sender.send(Err(outbound_failure_error(&error)));
+                        }
+                        if let Some(sender) = pending_chunk_acks.remove(&request_id) {
+                            let _ = sender.send(Err(format!("Chunk is not delivered: {:?}", error)));
+                        }
+                        if let Some((_, sender)) = pending_proxy_requests.remove(&request_id) {
+                            // Also reached while the peer is an older gateway without a common protocol version
+                            let _ = sender.send(Err(outbound_failure_error(&error)));
+                        }
+                    }
 
                     SwarmEvent::Behaviour(ComposedEvent::RequestResponse(
                         RequestResponseEvent::ResponseSent { .. },
                     )) => {}
 
+                    SwarmEvent::Behaviour(ComposedEvent::WsStream(WsStreamEvent { peer, request_id, stream })) => {
+                        // Session is looked up in main loop, where websocket sessions are kept
+                        event_sender.send(Event::WsStreamFromClient {
+                            request_id,
+                            remote_peer_id: peer,
+                            stream,
+                        }).await.expect("Event receiver not to be dropped.");
+                    }
+
                     SwarmEvent::Behaviour(ComposedEvent::Kademlia(KademliaEvent::RoutingUpdated {
                         peer, is_new_peer, addresses, bucket_range, old_peer
                     })) => {
@@ -369,6 +991,35 @@ pub async fn network_event_loop(
                         // let _ = sender.send(());
                     }
 
+                    SwarmEvent::Behaviour(ComposedEvent::Kademlia(KademliaEvent::OutboundQueryCompleted {
+                        id,
+                        result: QueryResult::GetClosestPeers(result),
+                        ..
+                    })) => {
+                        if let Some(peer) = pending_peer_lookups.remove(&id) {
+                            let found = match result {
+                                Ok(GetClosestPeersOk { peers, .. }) => peers.contains(&peer),
+                                Err(e) => {
+                                    warn!("[libp2p] Lookup for peer {} failed: {:?}", peer, e);
+                                    false
+                                }
+                            };
+
+                            let dial_result = if found {
+                                swarm.dial(&peer).map_err(|e| format!("Dial peer {} failed: {:?}", peer, e))
+                            } else {
+                                Err(format!("Peer {} not found in the network", peer))
+                            };
+                            if let Err(e) = dial_result {
+                                if let Some(senders) = pending_dials.remove(&peer) {
+                                    for sender in senders {
+                                        let _ = sender.send(Err(e.clone()));
+                                    }
+                                }
+                            }
+                        }
+                    }
+
                     SwarmEvent::Behaviour(ComposedEvent::Kademlia(
                         KademliaEvent::OutboundQueryCompleted {
                             id,
@@ -394,28 +1045,91 @@ pub async fn network_event_loop(
                         // Commands for libp2p
                         Command::PublishGossip { data } => {
                             info!("[libp2p] publish local new message to remote: {}", String::from_utf8_lossy(&data));
-                            swarm.behaviour_mut().gossipsub.publish(topic.clone(), data).unwrap();
+                            match SignedAnnouncement::sign(&local_key, data) {
+                                Ok(announcement) => {
+                                    if let Ok(service) = serde_json::from_slice::<ApronService>(&announcement.payload) {
+                                        set(announcements.clone(), service.id, announcement.clone());
+                                    }
+                                    if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), serde_json::to_vec(&announcement).unwrap()) {
+                                        warn!("[libp2p] Publish message failed: {:?}", e);
+                                    }
+                                }
+                                Err(e) => error!("[libp2p] Sign message failed: {}", e),
+                            }
+                        }
+                        Command::PublishHealth { data } => {
+                            info!("[libp2p] publish provider health to remote: {}", String::from_utf8_lossy(&data));
+                            let published = SignedAnnouncement::sign(&local_key, data).and_then(|announcement| {
+                                swarm.behaviour_mut()
+                                    .gossipsub
+                                    .publish(health_topic.clone(), serde_json::to_vec(&announcement).unwrap())
+                                    .map_err(|e| format!("{:?}", e))
+                            });
+                            if let Err(e) = published {
+                                warn!("[libp2p] Publish provider health failed: {}", e);
+                            }
                         }
                         Command::Dial { peer, peer_addr} => {
                             info!("[libp2p] Dial to peer: {}, peer_addr: {:?}", peer.to_string(), peer_addr);
                         //    swarm.dial_addr(peer_addr.with(Protocol::P2p(peer.into())));
                         }
+                        Command::EnsureConnected { peer, sender } => {
+                            if peer == *swarm.local_peer_id() {
+                                let _ = sender.send(Err(String::from("Can't forward request to local peer")));
+                            } else if swarm.is_connected(&peer) {
+                                let _ = sender.send(Ok(()));
+                            } else if let Some(senders) = pending_dials.get_mut(&peer) {
+                                // Dialing is already in progress
+                                senders.push(sender);
+                            } else {
+                                info!("[libp2p] Dial to service peer: {}", peer.to_string());
+                                pending_dials.insert(peer, vec![sender]);
+                                if let Err(e) = swarm.dial(&peer) {
+                                    // No usable address known yet, locate the peer with kademlia
+                                    info!("[libp2p] Dial {} failed: {:?}, looking up with kademlia", peer, e);
+                                    let query_id = swarm.behaviour_mut().kademlia.get_closest_peers(peer);
+                                    pending_peer_lookups.insert(query_id, peer);
+                                }
+                            }
+                        }
 
                         // Commands for proxy data
-                        Command::SendRequest { peer, request_id, data } => {
-                            info!("[libp2p] Send request to peer: {}, data: {}", peer.to_string(), String::from_utf8_lossy(&data));
-                            swarm.behaviour_mut().request_response.send_request(&peer, DataExchangeRequest{schema: 0, data});
+                        Command::SendRequest { peer, info, sender } => {
+                            info!("[libp2p] Send request to peer: {}, info: {:?}", peer.to_string(), info);
+                            let client_request_id = info.request_id.clone();
+                            let request_id = swarm.behaviour_mut().request_response.send_request(&peer, ProxyMessage::InitProxy(info));
+                            pending_proxy_requests.insert(request_id, (client_request_id, sender));
                         }
-                        Command::SendProxyData { peer, data } => {
-                            info!("[libp2p] Send proxy data to peer: {}, data: {}", peer.to_string(), String::from_utf8_lossy(&data));
-                            swarm.behaviour_mut().request_response.send_request(&peer, DataExchangeRequest{schema: 1, data});
+                        Command::SendHttpRequest { peer, info, sender } => {
+                            info!("[libp2p] Send http request to peer: {}, info: {:?}", peer.to_string(), info);
+                            let client_request_id = info.request_id.clone();
+                            let request_id = swarm.behaviour_mut().request_response.send_request(&peer, ProxyMessage::InitProxy(info));
+                            pending_http_requests.insert(request_id, (client_request_id, sender));
                         }
-                        Command::SendProxyDataFromService { peer, request_id, data } => {
-                            info!("[libp2p] Send proxy data to peer: {}, data: {}", peer.to_string(), String::from_utf8_lossy(&data));
-                            swarm.behaviour_mut().request_response.send_request(&peer, DataExchangeRequest{schema: 2, data});
+                        Command::SendBodyChunk { peer, chunk, sender } => {
+                            debug!("[libp2p] Send body chunk {} of {} to peer: {}", chunk.seq, chunk.request_id, peer.to_string());
+                            let request_id = swarm.behaviour_mut().request_response.send_request(&peer, ProxyMessage::BodyChunk(chunk));
+                            pending_chunk_acks.insert(request_id, sender);
                         }
-                        Command::SendResponse { data, channel } => {
-                            swarm.behaviour_mut().request_response.send_response( channel, FileResponse(data)).unwrap();
+                        Command::SendClose { peer, request_id } => {
+                            info!("[libp2p] Send close of {} to peer: {}", request_id, peer.to_string());
+                            // Forget state of the request, its response is no longer awaited
+                            pending_http_requests.retain(|_, (_, sender)| !sender.is_canceled());
+                            body_streams.remove(&(peer, request_id.clone()));
+                            swarm.behaviour_mut().request_response.send_request(&peer, ProxyMessage::Close { request_id });
+                        }
+                        Command::SendError { peer, request_id, error } => {
+                            warn!("[libp2p] Send error of {} to peer: {}, error: {}", request_id, peer.to_string(), error);
+                            swarm.behaviour_mut().request_response.send_request(&peer, ProxyMessage::Error { request_id, error });
+                        }
+                        Command::OpenWsStream { peer, request_id, sender } => {
+                            info!("[libp2p] Open ws stream of {} to peer: {}", request_id, peer.to_string());
+                            swarm.behaviour_mut().ws_stream.open(peer, request_id, sender);
+                        }
+                        Command::SendResponse { response, channel } => {
+                            if swarm.behaviour_mut().request_response.send_response(channel, response).is_err() {
+                                warn!("[libp2p] Response dropped, connection to peer is closed");
+                            }
                         }
                         Command::AddService {args} => {
                             if opt.market_contract_addr == "" {
@@ -459,110 +1173,129 @@ pub async fn network_event_loop(
     // println!("network_event_loop ended");
 }
 
-#[derive(Debug, Clone)]
-pub struct DataExchangeProtocol();
-
-#[derive(Clone)]
-pub struct DataExchangeCodec();
-
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
-pub struct DataExchangeRequest {
-    pub(crate) schema: u8,
-
-    // 0 for InitRequest sent from client
-    // 1 for ws data sent from client side
-    // 2 for ws data sent from service side
-    // 3 for http data sent from service side
-    pub(crate) data: Vec<u8>,
-}
-
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct FileResponse(pub(crate) Vec<u8>);
-
-//    pub struct Payload {
-//        pub schema: String,
-//        pub data: Vec<u8>,
-//    }
-
-impl ProtocolName for DataExchangeProtocol {
-    fn protocol_name(&self) -> &[u8] {
-        "/file-exchange/1".as_bytes()
+// Pass response to the client session waiting for it, and acknowledge the result.
+// Sender is cloned out of the mapping, whose own slot always takes the response,
+// so the event loop never waits for a slow session.
+fn deliver_to_client_session(
+    req_id_client_session_mapping: AppState<mpsc::Sender<HttpProxyResponse>>,
+    resp: HttpProxyResponse,
+) -> ProxyResponse {
+    let request_id = resp.request_id.clone();
+    match get(req_id_client_session_mapping, request_id.clone()) {
+        Some(mut sender) => match sender.try_send(resp) {
+            Ok(()) => ProxyResponse::Ack,
+            Err(_) => ProxyResponse::Error(GatewayError::NotFound(format!(
+                "Session {} is closed",
+                request_id
+            ))),
+        },
+        None => ProxyResponse::Error(GatewayError::NotFound(format!(
+            "Session {} not found",
+            request_id
+        ))),
     }
 }
 
-#[async_trait]
-impl RequestResponseCodec for DataExchangeCodec {
-    type Protocol = DataExchangeProtocol;
-    type Request = DataExchangeRequest;
-    type Response = FileResponse;
-
-    async fn read_request<T>(
-        &mut self,
-        _: &DataExchangeProtocol,
-        io: &mut T,
-    ) -> io::Result<Self::Request>
-    where
-        T: AsyncRead + Unpin + Send,
-    {
-        let schema_fields = read_length_prefixed(io, 1).await?;
-        let data = read_length_prefixed(io, 1_000_000).await?;
-
-        if schema_fields.is_empty() || data.is_empty() {
-            return Err(io::ErrorKind::UnexpectedEof.into());
+// Error reported to client when a request can't be delivered to service side gateway
+fn outbound_failure_error(error: &OutboundFailure) -> GatewayError {
+    match error {
+        OutboundFailure::Timeout => {
+            GatewayError::GatewayTimeout(String::from("Service gateway did not respond in time"))
         }
-
-        Ok(DataExchangeRequest {
-            schema: schema_fields[0],
-            data,
-        })
-    }
-
-    async fn read_response<T>(
-        &mut self,
-        _: &DataExchangeProtocol,
-        io: &mut T,
-    ) -> io::Result<Self::Response>
-    where
-        T: AsyncRead + Unpin + Send,
-    {
-        let vec = read_length_prefixed(io, 1_000_000).await?;
-
-        if vec.is_empty() {
-            return Err(io::ErrorKind::UnexpectedEof.into());
+        OutboundFailure::DialFailure => {
+            GatewayError::ServiceUnavailable(String::from("Service gateway is unreachable"))
+        }
+        // Older gateways without a common protocol version end up here
+        OutboundFailure::UnsupportedProtocols => GatewayError::BadGateway(String::from(
+            "Service gateway doesn't support the proxy protocol",
+        )),
+        OutboundFailure::ConnectionClosed => {
+            GatewayError::BadGateway(String::from("Connection to service gateway is closed"))
         }
-
-        Ok(FileResponse(vec))
-        //  Ok(FileResponse(String::from_utf8(vec).unwrap()))
     }
+}
 
-    async fn write_request<T>(
-        &mut self,
-        _: &DataExchangeProtocol,
-        io: &mut T,
-        DataExchangeRequest { schema, data }: DataExchangeRequest,
-    ) -> io::Result<()>
-    where
-        T: AsyncWrite + Unpin + Send,
-    {
-        write_length_prefixed(io, [schema]).await?;
-        write_length_prefixed(io, data).await?;
-        io.close().await?;
-
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    // Send body to a peer which rejects chunk `rejected_seq`, returns chunks and errors sent to it
+    fn send_chunks(
+        pieces: Vec<Result<Bytes, String>>,
+        rejected_seq: Option<u64>,
+    ) -> (Result<(), String>, Vec<BodyChunk>, Vec<GatewayError>) {
+        let (command_sender, mut command_receiver) = mpsc::channel(0);
+        let sending = send_body_chunks(
+            command_sender,
+            PeerId::random(),
+            "req".to_string(),
+            stream::iter(pieces),
+        );
+        // Acknowledged the way network event loop does once the peer replied
+        let receiving = async {
+            let (mut chunks, mut errors) = (Vec::new(), Vec::new());
+            while let Some(command) = command_receiver.next().await {
+                match command {
+                    Command::SendBodyChunk { chunk, sender, .. } => {
+                        let ack = if Some(chunk.seq) == rejected_seq {
+                            Err(String::from("Rejected"))
+                        } else {
+                            Ok(())
+                        };
+                        let _ = sender.send(ack);
+                        chunks.push(chunk);
+                    }
+                    Command::SendError { error, .. } => errors.push(error),
+                    _ => panic!("Unexpected command"),
+                }
+            }
+            (chunks, errors)
+        };
+        let (result, (chunks, errors)) = block_on(future::join(sending, receiving));
+        (result, chunks, errors)
     }
 
-    async fn write_response<T>(
-        &mut self,
-        _: &DataExchangeProtocol,
-        io: &mut T,
-        FileResponse(data): FileResponse,
-    ) -> io::Result<()>
-    where
-        T: AsyncWrite + Unpin + Send,
-    {
-        write_length_prefixed(io, data).await?;
-        io.close().await?;
+    #[test]
+    fn test_send_body_in_chunks() {
+        let data: Vec<u8> = (0..2 * BODY_CHUNK_SIZE + 10).map(|i| i as u8).collect();
+        let pieces = vec![
+            Ok(Bytes::copy_from_slice(&data[..10])),
+            Ok(Bytes::copy_from_slice(&data[10..])),
+        ];
+        let (result, chunks, errors) = send_chunks(pieces, None);
+        assert!(result.is_ok() && errors.is_empty());
+
+        // Large piece is split to fit in messages, end of body is marked with an empty chunk
+        let sizes: Vec<usize> = chunks.iter().map(|chunk| chunk.data.len()).collect();
+        assert_eq!(sizes, vec![10, BODY_CHUNK_SIZE, BODY_CHUNK_SIZE, 0]);
+        for (seq, chunk) in chunks.iter().enumerate() {
+            assert_eq!(chunk.seq, seq as u64);
+            assert_eq!(chunk.is_last, seq == 3);
+        }
+        let received: Vec<u8> = chunks.into_iter().flat_map(|chunk| chunk.data).collect();
+        assert_eq!(received, data);
+    }
 
-        Ok(())
+    #[test]
+    fn test_send_body_stops_on_failure() {
+        // Peer is told once the body breaks
+        let pieces = vec![Ok(Bytes::from_static(b"head")), Err(String::from("reset"))];
+        let (result, chunks, errors) = send_chunks(pieces, None);
+        assert!(result.is_err());
+        assert_eq!(chunks.len(), 1);
+        assert!(matches!(errors[..], [GatewayError::BadGateway(_)]));
+
+        // Nothing more is sent once the peer rejected a chunk
+        let pieces = vec![
+            Ok(Bytes::from_static(b"1")),
+            Ok(Bytes::from_static(b"2")),
+            Ok(Bytes::from_static(b"3")),
+        ];
+        let (result, chunks, errors) = send_chunks(pieces, Some(1));
+        assert_eq!(result, Err(String::from("Rejected")));
+        assert_eq!(chunks.len(), 2);
+        assert!(errors.is_empty());
     }
 }
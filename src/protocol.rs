@@ -0,0 +1,265 @@
+use async_std::io;
+use async_trait::async_trait;
+use futures::prelude::*;
+use futures::AsyncWriteExt;
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName};
+use libp2p::request_response::RequestResponseCodec;
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::announcement::SignedAnnouncement;
+use crate::forward_service_models::{BodyChunk, HttpProxyResponse, ProxyRequestInfo};
+use crate::gateway_error::GatewayError;
+use crate::service::ServiceDigest;
+
+// Max size of one encoded message
+pub(crate) const MAX_MESSAGE_SIZE: usize = 1_000_000;
+/// Max size of body sent in one message, larger bodies are split into chunks.
+pub const BODY_CHUNK_SIZE: usize = 256 * 1024;
+/// Max size of websocket message relayed in one frame of session stream, leaving room for encoding.
+pub const MAX_WS_MESSAGE_SIZE: usize = 900 * 1024;
+// Max size of announcements in one RegistryEntries response, leaving room for encoding
+const MAX_REGISTRY_PAGE_SIZE: usize = MAX_MESSAGE_SIZE - 1024;
+
+/// Versions of the proxy protocol, negotiated while opening substream.
+/// Newer versions should be added in front so they are preferred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    V1,
+}
+
+impl ProxyProtocol {
+    /// All versions supported by this gateway, most preferred first.
+    pub fn supported() -> Vec<ProxyProtocol> {
+        vec![ProxyProtocol::V1]
+    }
+}
+
+impl ProtocolName for ProxyProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        match self {
+            ProxyProtocol::V1 => b"/apron/proxy/1.0.0",
+        }
+    }
+}
+
+/// Messages exchanged between gateways, each one is sent as a request and
+/// acknowledged with a `ProxyResponse`. Frames of websocket sessions are not
+/// sent this way, but over a substream of each session, see `ws_stream`.
+/// Variants are encoded by position, so new kinds must be added right before
+/// `Unsupported`, which is never sent. Removing or reordering variants, or changing
+/// layout of their payloads, breaks peers of the same version, which needs a new
+/// `ProxyProtocol` version.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ProxyMessage {
+    // Init http or websocket request, sent from client side gateway
+    InitProxy(ProxyRequestInfo),
+    // Registry digests sent while connected, replied with services missing or outdated
+    RegistrySync(Vec<ServiceDigest>),
+    // Request can't be processed by the peer
+    Error {
+        request_id: String,
+        error: GatewayError,
+    },
+    // Session is closed by the peer
+    Close {
+        request_id: String,
+    },
+    // Piece of http request or response body
+    BodyChunk(BodyChunk),
+    // Message can't be decoded, most likely a kind added by newer gateway.
+    // It is produced by codec only and never sent.
+    Unsupported(String),
+}
+
+/// Acknowledgement of a `ProxyMessage`, whose layout is versioned the same way.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ProxyResponse {
+    // Message is accepted
+    Ack,
+    // Message is rejected with reason
+    Error(GatewayError),
+    // Message kind is unknown to the receiver
+    Unsupported,
+    // Signed announcements of services replied to RegistrySync
+    RegistryEntries(Vec<SignedAnnouncement>),
+    // Response of upstream service replied to http InitProxy
+    HttpResponse(HttpProxyResponse),
+}
+
+/// Announcements replied in one `RegistryEntries` response, taken in order until the page is full.
+/// The peer asks again for the rest, with digests updated by the services received.
+/// An announcement too large to ever fit is left out.
+pub fn registry_page(announcements: Vec<SignedAnnouncement>) -> Vec<SignedAnnouncement> {
+    let mut page = Vec::new();
+    let mut page_size = 0;
+    for announcement in announcements {
+        let size = bincode::serialized_size(&announcement).unwrap_or(u64::MAX) as usize;
+        if size > MAX_REGISTRY_PAGE_SIZE {
+            error!(
+                "Announcement of {} bytes is too large for registry sync",
+                size
+            );
+            continue;
+        }
+        if page_size + size > MAX_REGISTRY_PAGE_SIZE {
+            break;
+        }
+        page_size += size;
+        page.push(announcement);
+    }
+    page
+}
+
+#[derive(Clone)]
+pub struct ProxyCodec();
+
+fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+    bincode::serialize(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn decode_message(data: &[u8]) -> ProxyMessage {
+    bincode::deserialize(data).unwrap_or_else(|e| ProxyMessage::Unsupported(e.to_string()))
+}
+
+#[async_trait]
+impl RequestResponseCodec for ProxyCodec {
+    type Protocol = ProxyProtocol;
+    type Request = ProxyMessage;
+    type Response = ProxyResponse;
+
+    async fn read_request<T>(&mut self, _: &ProxyProtocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, MAX_MESSAGE_SIZE).await?;
+        if data.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(decode_message(&data))
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &ProxyProtocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, MAX_MESSAGE_SIZE).await?;
+        if data.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(bincode::deserialize(&data).unwrap_or(ProxyResponse::Unsupported))
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &ProxyProtocol,
+        io: &mut T,
+        message: ProxyMessage,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, encode(&message)?).await?;
+        io.close().await?;
+
+        Ok(())
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &ProxyProtocol,
+        io: &mut T,
+        response: ProxyResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, encode(&response)?).await?;
+        io.close().await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_unknown_message() {
+        let message = ProxyMessage::Close {
+            request_id: "req".to_string(),
+        };
+        match decode_message(&encode(&message).unwrap()) {
+            ProxyMessage::Close { request_id } => assert_eq!(request_id, "req"),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+
+        // Variant index not known by this version
+        let unknown = encode(&(100u32, "payload")).unwrap();
+        assert!(matches!(
+            decode_message(&unknown),
+            ProxyMessage::Unsupported(_)
+        ));
+    }
+
+    #[test]
+    fn test_registry_page() {
+        let announcement = |size: usize| SignedAnnouncement {
+            public_key: vec![],
+            payload: vec![0; size],
+            signature: vec![],
+        };
+        let page = registry_page(vec![
+            announcement(400_000),
+            announcement(MAX_MESSAGE_SIZE),
+            announcement(400_000),
+            announcement(400_000),
+            announcement(10),
+        ]);
+        assert_eq!(
+            page.iter().map(|a| a.payload.len()).collect::<Vec<_>>(),
+            vec![400_000, 400_000]
+        );
+        let response = encode(&ProxyResponse::RegistryEntries(page)).unwrap();
+        assert!(response.len() <= MAX_MESSAGE_SIZE);
+    }
+
+    // Layout of messages on the wire, which is fixed within a protocol version
+    #[test]
+    fn test_wire_layout() {
+        let close = ProxyMessage::Close {
+            request_id: "r".to_string(),
+        };
+        assert_eq!(
+            encode(&close).unwrap(),
+            vec![3, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, b'r']
+        );
+
+        let chunk = ProxyMessage::BodyChunk(BodyChunk {
+            request_id: "r".to_string(),
+            seq: 2,
+            data: vec![7],
+            is_last: true,
+        });
+        assert_eq!(
+            encode(&chunk).unwrap(),
+            vec![
+                4, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, b'r', 2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0,
+                0, 0, 7, 1
+            ]
+        );
+
+        assert_eq!(encode(&ProxyResponse::Ack).unwrap(), vec![0, 0, 0, 0]);
+        assert_eq!(
+            encode(&ProxyResponse::RegistryEntries(Vec::new())).unwrap(),
+            vec![3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use std::cmp::min;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::forward_service_models::ProxyRequestInfo;
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 2;
+const DEFAULT_BACKOFF_MS: u64 = 100;
+const DEFAULT_MAX_BACKOFF_MS: u64 = 2000;
+const DEFAULT_RETRYABLE_STATUS_CODES: [u16; 3] = [502, 503, 504];
+
+// Methods which can be sent again without changing the outcome
+const IDEMPOTENT_METHODS: [&str; 6] = ["GET", "HEAD", "OPTIONS", "PUT", "DELETE", "TRACE"];
+
+/// Retry settings of requests proxied to a service, all fields are optional.
+#[derive(Deserialize, Debug, Serialize, PartialEq, Clone, Default)]
+pub struct RetryPolicy {
+    // Attempts of a request including the first one, 1 disables retries
+    pub max_attempts: Option<u32>,
+    // Delay before the first retry, doubled for every following one
+    pub backoff_ms: Option<u64>,
+    pub max_backoff_ms: Option<u64>,
+    // Statuses worth another attempt, 502, 503 and 504 by default
+    pub retryable_status_codes: Option<Vec<u16>>,
+    // Also retry methods which are not idempotent, such as POST
+    pub retry_non_idempotent: Option<bool>,
+}
+
+impl RetryPolicy {
+    /// Whether a request failed with status on its `attempt`th attempt should be sent again.
+    /// Requests whose body is sent in chunks can't be replayed.
+    pub fn should_retry(
+        &self,
+        req_info: &ProxyRequestInfo,
+        attempt: u32,
+        status_code: u16,
+    ) -> bool {
+        if attempt >= self.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS) || req_info.has_more_body {
+            return false;
+        }
+        let is_idempotent = IDEMPOTENT_METHODS
+            .iter()
+            .any(|method| method.eq_ignore_ascii_case(&req_info.http_method));
+        if !is_idempotent && !self.retry_non_idempotent.unwrap_or(false) {
+            return false;
+        }
+        match &self.retryable_status_codes {
+            Some(status_codes) => status_codes.contains(&status_code),
+            None => DEFAULT_RETRYABLE_STATUS_CODES.contains(&status_code),
+        }
+    }
+
+    /// Delay before sending the request again after its `attempt`th attempt.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let base = self.backoff_ms.unwrap_or(DEFAULT_BACKOFF_MS);
+        let factor = 1u64 << min(attempt.saturating_sub(1), 16);
+        Duration::from_millis(min(
+            base.saturating_mul(factor),
+            self.max_backoff_ms.unwrap_or(DEFAULT_MAX_BACKOFF_MS),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::new_request;
+
+    #[test]
+    fn test_retry_idempotent_only() {
+        let policy = RetryPolicy::default();
+        assert!(policy.should_retry(&new_request("GET"), 1, 503));
+        assert!(!policy.should_retry(&new_request("GET"), 2, 503));
+        assert!(!policy.should_retry(&new_request("GET"), 1, 500));
+        assert!(!policy.should_retry(&new_request("POST"), 1, 503));
+
+        let policy = RetryPolicy {
+            max_attempts: Some(4),
+            retry_non_idempotent: Some(true),
+            ..Default::default()
+        };
+        assert!(policy.should_retry(&new_request("POST"), 3, 502));
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(10), Duration::from_millis(2000));
+    }
+}
@@ -1,6 +1,7 @@
+use crate::circuit_breaker::list_tripped_circuits;
 use crate::service::{
-    delete_service, get_services, list_local_services, list_remote_services, list_service_peers,
-    new_update_service,
+    delete_service, get_service_health, get_services, list_local_services, list_remote_services,
+    list_service_peers, new_update_service,
 };
 use actix_web::web;
 
@@ -9,10 +10,12 @@ pub fn routes(cfg: &mut web::ServiceConfig) {
         web::scope("/service")
             .route("", web::get().to(get_services))
             .route("", web::post().to(new_update_service))
-            .route("", web::delete().to(delete_service)),
+            .route("", web::delete().to(delete_service))
+            .route("/{id}/health", web::get().to(get_service_health)),
     );
     cfg.service(web::scope("/local").route("", web::get().to(list_local_services)));
     cfg.service(web::scope("/remote").route("", web::get().to(list_remote_services)));
     cfg.service(web::scope("/peers").route("", web::get().to(list_service_peers)));
     cfg.service(web::scope("/report").route("", web::get().to(get_services)));
+    cfg.service(web::scope("/circuits").route("", web::get().to(list_tripped_circuits)));
 }
@@ -1,7 +1,10 @@
-use std::collections::HashSet;
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
 use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use actix_web::web::{Data, HttpResponse, Json};
+use actix_web::error::ErrorForbidden;
+use actix_web::web::{self, Data, HttpResponse, Json};
 use actix_web::Error;
 use futures::channel::mpsc;
 use futures::SinkExt;
@@ -10,9 +13,14 @@ use serde::Deserialize;
 use serde::Serialize;
 
 use crate::contract::{add_service, call, exec};
+use crate::header_rules::HeaderRules;
+use crate::health_check::{HealthCheckConfig, ProviderHealth, RemoteHealth};
 use crate::helpers::respond_json;
+use crate::load_balancer::LoadBalanceStrategy;
 use crate::network::Command;
+use crate::retry::RetryPolicy;
 use crate::state::{all, set, values, AppState};
+use crate::timeouts::TimeoutConfig;
 
 #[derive(Deserialize, Debug, Serialize, PartialEq, Clone)]
 pub struct ApronServiceProvider {
@@ -24,6 +32,9 @@ pub struct ApronServiceProvider {
     pub created_at: Option<i64>,
     pub updated_at: Option<i64>,
     pub extra_detail: Option<String>,
+    // Relative weight used by weighted load balance strategy, 1 by default
+    pub weight: Option<u32>,
+    pub health_check: Option<HealthCheckConfig>,
 }
 
 #[derive(Deserialize, Debug, Serialize, PartialEq, Clone)]
@@ -44,9 +55,50 @@ pub struct ApronService {
     pub price_plan: Option<String>,
 
     pub user_id: Option<String>,
+
+    // Strategy to select provider for each request, round robin by default
+    pub lb_strategy: Option<LoadBalanceStrategy>,
+
+    // Timeouts of requests proxied to the service, defaults are used if not set
+    pub timeouts: Option<TimeoutConfig>,
+    // Retries of failed requests, only idempotent requests are retried by default
+    pub retry: Option<RetryPolicy>,
+    // Rewriting of request and response headers, applied by the gateway serving the service
+    pub header_rules: Option<HeaderRules>,
+
+    // Version of the entry, increased by owning peer on every change, newer version wins
+    pub version: Option<u64>,
+    // Peer which made this version of the entry, only the owning peer is allowed to
+    pub origin_peer_id: Option<String>,
+}
+
+/// Summary of a service entry, exchanged to find out entries missing or outdated between gateways.
+#[derive(Deserialize, Debug, Serialize, PartialEq, Clone)]
+pub struct ServiceDigest {
+    pub id: String,
+    pub version: u64,
 }
 
 impl ApronService {
+    pub fn digest(&self) -> ServiceDigest {
+        ServiceDigest {
+            id: self.id.clone(),
+            version: self.version.unwrap_or(0),
+        }
+    }
+
+    // Deleted services are kept as tombstone, so that outdated updates can't bring them back
+    pub fn is_active(&self) -> bool {
+        self.is_deleted != Some(true)
+    }
+
+    // Mark the entry as a new version made by peer
+    fn bump_version(&mut self, peer_id: &PeerId) {
+        let next = max(self.version.unwrap_or(0) + 1, now_millis());
+        self.version = Some(next);
+        self.origin_peer_id = Some(peer_id.to_base58());
+    }
+
     // service - serviceprovide in a 1-1 relationship
     pub fn apronservice_to_args(self) -> Vec<String> {
         let provider = self.providers.unwrap()[0].clone();
@@ -85,9 +137,6 @@ impl ApronService {
         if other.user_id.is_some() {
             self.user_id = other.user_id;
         }
-        if other.peer_id.is_some() {
-            self.peer_id = other.peer_id;
-        }
         if other.name.is_some() {
             self.name = other.name;
         }
@@ -103,6 +152,18 @@ impl ApronService {
         if other.usage.is_some() {
             self.usage = other.usage;
         }
+        if other.lb_strategy.is_some() {
+            self.lb_strategy = other.lb_strategy;
+        }
+        if other.timeouts.is_some() {
+            self.timeouts = other.timeouts;
+        }
+        if other.retry.is_some() {
+            self.retry = other.retry;
+        }
+        if other.header_rules.is_some() {
+            self.header_rules = other.header_rules;
+        }
         // update ApronServiceProvider
         if other.providers.is_some() {
             if self.providers.is_some() {
@@ -127,35 +188,17 @@ impl ApronService {
             }
         }
     }
-
-    pub fn get_http_provider(&self) -> Option<String> {
-        self.get_provider(String::from("http"))
-    }
-
-    pub fn get_ws_provider(&self) -> Option<String> {
-        self.get_provider(String::from("ws"))
-    }
-
-    fn get_provider(&self, schema: String) -> Option<String> {
-        let mut rslt: Option<String> = None;
-        if self.providers.is_some() {
-            for p in self.providers.as_ref().unwrap() {
-                if p.schema.is_some() && p.schema.as_ref().unwrap().starts_with(&schema) {
-                    rslt = Option::from(String::from(format!(
-                        "{}://{}",
-                        p.schema.as_ref().unwrap(),
-                        p.base_url.as_ref().unwrap()
-                    )));
-                    break;
-                }
-            }
-        }
-
-        rslt
-    }
 }
 
 impl ApronServiceProvider {
+    pub fn url(&self) -> String {
+        format!(
+            "{}://{}",
+            self.schema.clone().unwrap_or_default(),
+            self.base_url.clone().unwrap_or_default()
+        )
+    }
+
     pub fn update(&mut self, other: ApronServiceProvider) {
         if other.id.is_some() {
             self.id = other.id;
@@ -181,7 +224,103 @@ impl ApronServiceProvider {
         if other.extra_detail.is_some() {
             self.extra_detail = other.extra_detail;
         }
+        if other.weight.is_some() {
+            self.weight = other.weight;
+        }
+        if other.health_check.is_some() {
+            self.health_check = other.health_check;
+        }
+    }
+}
+
+/// Digests of all services in registry.
+pub fn registry_digests(data: AppState<ApronService>) -> Vec<ServiceDigest> {
+    values(data)
+        .unwrap()
+        .iter()
+        .map(|service| service.digest())
+        .collect()
+}
+
+/// Digests sent to a peer to continue registry sync, after the peer replied with some of
+/// the services. Services received are described with their received version if it is
+/// newer, so they are not sent again even if they were rejected.
+pub fn registry_sync_digests(
+    data: AppState<ApronService>,
+    received: &HashMap<String, u64>,
+) -> Vec<ServiceDigest> {
+    let mut versions = received.clone();
+    for digest in registry_digests(data) {
+        let version = versions.entry(digest.id).or_insert(digest.version);
+        *version = max(*version, digest.version);
+    }
+    versions
+        .into_iter()
+        .map(|(id, version)| ServiceDigest { id, version })
+        .collect()
+}
+
+/// Services in registry which are missing or outdated in the registry described by digests.
+pub fn services_newer_than(
+    data: AppState<ApronService>,
+    digests: &[ServiceDigest],
+) -> Vec<ApronService> {
+    let remote_versions: HashMap<&str, u64> = digests
+        .iter()
+        .map(|digest| (digest.id.as_str(), digest.version))
+        .collect();
+    values(data)
+        .unwrap()
+        .into_iter()
+        .filter(|service| match remote_versions.get(service.id.as_str()) {
+            Some(version) => service.version.unwrap_or(0) > *version,
+            None => true,
+        })
+        .collect()
+}
+
+/// Get a service which is not deleted.
+pub fn get_active_service(data: AppState<ApronService>, id: String) -> Option<ApronService> {
+    crate::state::get(data, id).filter(|service| service.is_active())
+}
+
+/// Apply a service entry received from network.
+/// The entry must be made by its owning peer, and be newer than the saved one.
+pub fn apply_remote_service(
+    data: AppState<ApronService>,
+    update: ApronService,
+) -> Result<(), String> {
+    if update.origin_peer_id.is_none() || update.origin_peer_id != update.peer_id {
+        return Err(format!(
+            "service {} owned by {:?} is changed by {:?}",
+            update.id, update.peer_id, update.origin_peer_id
+        ));
+    }
+
+    let mut registry = data.lock().expect("Could not acquire lock");
+    if let Some(current) = registry.get(&update.id) {
+        if current.peer_id != update.peer_id {
+            return Err(format!(
+                "service {} is owned by {:?}, not {:?}",
+                update.id, current.peer_id, update.peer_id
+            ));
+        }
+        if update.version.unwrap_or(0) <= current.version.unwrap_or(0) {
+            return Err(format!(
+                "service {} version {:?} is not newer than {:?}",
+                update.id, update.version, current.version
+            ));
+        }
     }
+    registry.insert(update.id.clone(), update);
+    Ok(())
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
 }
 
 // #[derive(Debug,Serialize, PartialEq, Clone)]
@@ -199,12 +338,26 @@ pub async fn new_update_service(
 ) -> Result<Json<ApronService>, Error> {
     let key = info.id.clone();
     let mut new_service = info.into_inner();
+    let local_peer = Some(local_peer_id.to_base58());
 
     let service = crate::state::get(data.clone(), key.clone());
+    if let Some(service) = service.as_ref() {
+        if service.peer_id != local_peer {
+            return Err(ErrorForbidden(format!(
+                "Service {} is owned by peer {:?}",
+                key, service.peer_id
+            )));
+        }
+    }
+
     // check create or update
-    if service.is_some() {
+    if service
+        .as_ref()
+        .map_or(false, |service| service.is_active())
+    {
         let mut service = service.unwrap();
         service.update(new_service);
+        service.bump_version(&local_peer_id);
         crate::state::set(data, key.clone(), service.clone());
 
         // publish data to the whole p2p network
@@ -238,10 +391,13 @@ pub async fn new_update_service(
 
         respond_json(service)
     } else {
-        new_service.peer_id = Some(local_peer_id.clone().to_base58());
+        new_service.peer_id = local_peer;
+        new_service.is_deleted = None;
+        // Start from version of the tombstone if the service was deleted before
+        new_service.version = service.and_then(|service| service.version);
+        new_service.bump_version(&local_peer_id);
 
-        let mut new_service2 = new_service.clone();
-        new_service2.peer_id = Some(local_peer_id.clone().to_base58());
+        let new_service2 = new_service.clone();
 
         crate::state::set(data, key, new_service);
 
@@ -283,15 +439,25 @@ pub async fn delete_service(
     info: Json<ApronService>,
     data: AppState<ApronService>,
     p2p_handler: Data<SharedHandler>,
+    local_peer_id: Data<PeerId>,
 ) -> HttpResponse {
     let key = info.id.clone();
-    let service = crate::state::delete(data, key.clone());
+    let service = get_active_service(data.clone(), key.clone());
     match service {
         Some(service) => {
+            if service.peer_id != Some(local_peer_id.to_base58()) {
+                return HttpResponse::Forbidden().body(format!(
+                    "Service {} is owned by peer {:?}",
+                    key, service.peer_id
+                ));
+            }
+
+            // Keep a tombstone in registry
             let mut new_service = service.clone();
             new_service.is_deleted = Some(true);
+            new_service.bump_version(&local_peer_id);
+            crate::state::set(data, key.clone(), new_service.clone());
 
-            // state::delete(data.clone(), key.clone());
             println!("[mgmt] delete service: {}", key);
 
             // publish data to the whole p2p network
@@ -313,7 +479,11 @@ pub async fn delete_service(
 /// Get All services
 pub async fn get_services(data: AppState<ApronService>) -> HttpResponse {
     println!("[mgmt]: List All Available Service");
-    let hdata = values(data).unwrap();
+    let hdata: Vec<ApronService> = values(data)
+        .unwrap()
+        .into_iter()
+        .filter(|service| service.is_active())
+        .collect();
 
     // for debug
     // for (key, value) in &hdata {
@@ -322,6 +492,29 @@ pub async fn get_services(data: AppState<ApronService>) -> HttpResponse {
     HttpResponse::Ok().json(hdata)
 }
 
+/// Get health of all providers of a service
+pub async fn get_service_health(
+    service_id: web::Path<String>,
+    data: AppState<ApronService>,
+    health_data: AppState<ProviderHealth>,
+    remote_health: Data<RemoteHealth>,
+) -> HttpResponse {
+    let service_id = service_id.into_inner();
+    println!("[mgmt]: Get health of service {}", service_id);
+    if get_active_service(data, service_id.clone()).is_none() {
+        return HttpResponse::NotFound().body("");
+    }
+
+    // Local providers are checked by this gateway, remote ones are gossiped by their owner
+    let mut response: Vec<ProviderHealth> = values(health_data)
+        .unwrap()
+        .into_iter()
+        .filter(|health| health.service_id == service_id)
+        .collect();
+    response.extend(remote_health.of_service(&service_id));
+    HttpResponse::Ok().json(response)
+}
+
 pub async fn list_local_services(
     data: AppState<ApronService>,
     local_peer_id: Data<PeerId>,
@@ -331,7 +524,7 @@ pub async fn list_local_services(
     let hdata = all(data).unwrap();
     let peer_id = Some(local_peer_id.clone().to_base58());
     for value in hdata.values() {
-        if value.peer_id == peer_id {
+        if value.peer_id == peer_id && value.is_active() {
             // local service
             response.push(value.clone());
         }
@@ -349,7 +542,7 @@ pub async fn list_remote_services(
     let hdata = all(data).unwrap();
     let peer_id = Some(local_peer_id.clone().to_base58());
     for value in hdata.values() {
-        if value.peer_id != peer_id {
+        if value.peer_id != peer_id && value.is_active() {
             // local service
             response.push(value.clone());
         }
@@ -362,9 +555,198 @@ pub async fn list_service_peers(data: AppState<ApronService>) -> HttpResponse {
     println!("[mgmt]: List Available Service Peers");
     let mut response = HashSet::new();
     let hdata = all(data).unwrap();
-    for value in hdata.values() {
+    for value in hdata.values().filter(|value| value.is_active()) {
         response.insert(value.peer_id.clone());
     }
     // HttpResponse::Ok().body(serde_json::to_string(&response).unwrap())
     HttpResponse::Ok().json(response)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::new_state;
+    use crate::test_fixtures::{new_service, TEST_SERVICE_ID};
+
+    fn owned_service(owner: &PeerId, version: u64) -> ApronService {
+        ApronService {
+            peer_id: Some(owner.to_base58()),
+            origin_peer_id: Some(owner.to_base58()),
+            version: Some(version),
+            ..new_service(vec![])
+        }
+    }
+
+    #[test]
+    fn test_bump_version() {
+        let peer_id = PeerId::random();
+        let mut service = new_service(vec![]);
+        let before = now_millis();
+        service.bump_version(&peer_id);
+        assert!(service.version.unwrap() >= before);
+        assert_eq!(service.origin_peer_id, Some(peer_id.to_base58()));
+
+        // Versions ahead of the clock still increase
+        let ahead = now_millis() + 60_000;
+        service.version = Some(ahead);
+        service.bump_version(&peer_id);
+        assert_eq!(service.version, Some(ahead + 1));
+    }
+
+    #[test]
+    fn test_apply_newer_versions_of_owner() {
+        let data = new_state::<ApronService>();
+        let owner = PeerId::random();
+        assert!(apply_remote_service(data.clone(), owned_service(&owner, 10)).is_ok());
+
+        assert!(apply_remote_service(data.clone(), owned_service(&owner, 10)).is_err());
+        assert!(apply_remote_service(data.clone(), owned_service(&owner, 9)).is_err());
+        let service = ApronService {
+            name: Some("renamed".to_string()),
+            ..owned_service(&owner, 11)
+        };
+        assert!(apply_remote_service(data.clone(), service.clone()).is_ok());
+        assert_eq!(
+            get_active_service(data, TEST_SERVICE_ID.to_string()),
+            Some(service)
+        );
+    }
+
+    #[test]
+    fn test_reject_changes_of_other_peers() {
+        let data = new_state::<ApronService>();
+        let owner = PeerId::random();
+        let other = PeerId::random();
+
+        // Made by a peer other than the one it claims to be owned by
+        let service = ApronService {
+            origin_peer_id: Some(other.to_base58()),
+            ..owned_service(&owner, 10)
+        };
+        assert!(apply_remote_service(data.clone(), service).is_err());
+        let service = ApronService {
+            origin_peer_id: None,
+            ..owned_service(&owner, 10)
+        };
+        assert!(apply_remote_service(data.clone(), service).is_err());
+
+        // Taking over a service of another peer
+        assert!(apply_remote_service(data.clone(), owned_service(&owner, 10)).is_ok());
+        assert!(apply_remote_service(data.clone(), owned_service(&other, 20)).is_err());
+        let service = get_active_service(data, TEST_SERVICE_ID.to_string()).unwrap();
+        assert_eq!(service.peer_id, Some(owner.to_base58()));
+    }
+
+    #[test]
+    fn test_tombstone_is_not_revived_by_older_version() {
+        let data = new_state::<ApronService>();
+        let owner = PeerId::random();
+        assert!(apply_remote_service(data.clone(), owned_service(&owner, 10)).is_ok());
+        let tombstone = ApronService {
+            is_deleted: Some(true),
+            ..owned_service(&owner, 11)
+        };
+        assert!(apply_remote_service(data.clone(), tombstone).is_ok());
+        assert_eq!(
+            get_active_service(data.clone(), TEST_SERVICE_ID.to_string()),
+            None
+        );
+
+        assert!(apply_remote_service(data.clone(), owned_service(&owner, 10)).is_err());
+        assert_eq!(
+            get_active_service(data.clone(), TEST_SERVICE_ID.to_string()),
+            None
+        );
+
+        // Owner can register it again with a newer version
+        assert!(apply_remote_service(data.clone(), owned_service(&owner, 12)).is_ok());
+        assert!(get_active_service(data, TEST_SERVICE_ID.to_string()).is_some());
+    }
+
+    fn service_with_version(id: &str, version: u64) -> ApronService {
+        ApronService {
+            id: id.to_string(),
+            version: Some(version),
+            ..new_service(vec![])
+        }
+    }
+
+    fn digest(id: &str, version: u64) -> ServiceDigest {
+        ServiceDigest {
+            id: id.to_string(),
+            version,
+        }
+    }
+
+    fn sorted_ids(services: Vec<ApronService>) -> Vec<String> {
+        let mut ids: Vec<String> = services.into_iter().map(|service| service.id).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_services_newer_than_digests() {
+        let data = new_state::<ApronService>();
+        for (id, version) in &[("a", 1), ("b", 5), ("c", 3), ("d", 2)] {
+            set(
+                data.clone(),
+                id.to_string(),
+                service_with_version(id, *version),
+            );
+        }
+        let tombstone = ApronService {
+            is_deleted: Some(true),
+            ..service_with_version("e", 4)
+        };
+        set(data.clone(), "e".to_string(), tombstone);
+
+        let mut digests = registry_digests(data.clone());
+        digests.sort_by(|a, b| a.id.cmp(&b.id));
+        assert_eq!(
+            digests,
+            vec![
+                digest("a", 1),
+                digest("b", 5),
+                digest("c", 3),
+                digest("d", 2),
+                digest("e", 4)
+            ]
+        );
+
+        // Missing, older and tombstoned entries are sent, equal and newer ones are not
+        let remote = vec![
+            digest("a", 1),
+            digest("b", 4),
+            digest("c", 7),
+            digest("x", 9),
+        ];
+        assert_eq!(
+            sorted_ids(services_newer_than(data.clone(), &remote)),
+            vec!["b", "d", "e"]
+        );
+        assert!(services_newer_than(data.clone(), &digests).is_empty());
+        assert_eq!(services_newer_than(data, &[]).len(), 5);
+    }
+
+    #[test]
+    fn test_registry_sync_digests() {
+        let data = new_state::<ApronService>();
+        set(data.clone(), "a".to_string(), service_with_version("a", 5));
+        set(data.clone(), "b".to_string(), service_with_version("b", 1));
+
+        // Received versions are kept even if they were rejected or older than the saved one
+        let received: HashMap<String, u64> = vec![
+            ("a".to_string(), 3),
+            ("b".to_string(), 2),
+            ("c".to_string(), 8),
+        ]
+        .into_iter()
+        .collect();
+        let mut digests = registry_sync_digests(data, &received);
+        digests.sort_by(|a, b| a.id.cmp(&b.id));
+        assert_eq!(
+            digests,
+            vec![digest("a", 5), digest("b", 2), digest("c", 8)]
+        );
+    }
+}
@@ -1,15 +1,53 @@
 use actix_web::web::Data;
 use std::collections::HashMap;
+use std::fmt::Debug;
 use std::sync::Mutex;
 
-// State is just a hashmap
-pub type State<T> = HashMap<String, T>;
+/// Storage backend of application state, entries are indexed by string key.
+pub trait Storage<T>: Debug + Send {
+    fn get(&self, key: &str) -> Option<&T>;
+    fn insert(&mut self, key: String, value: T) -> Option<T>;
+    fn remove(&mut self, key: &str) -> Option<T>;
+    fn entries(&self) -> &HashMap<String, T>;
+}
+
+// In memory storage is just a hashmap
+impl<T: Debug + Send> Storage<T> for HashMap<String, T> {
+    fn get(&self, key: &str) -> Option<&T> {
+        HashMap::get(self, key)
+    }
+
+    fn insert(&mut self, key: String, value: T) -> Option<T> {
+        HashMap::insert(self, key, value)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<T> {
+        HashMap::remove(self, key)
+    }
+
+    fn entries(&self) -> &HashMap<String, T> {
+        self
+    }
+}
+
+pub type State<T> = Box<dyn Storage<T>>;
 pub type AppState<T> = Data<Mutex<State<T>>>;
 
-/// Create a new state instance and wrap in a mutex.
+/// Create a new in memory state instance and wrap in a mutex.
 /// Further wrap into an Actix Data instance.
-pub fn new_state<T>() -> AppState<T> {
-    let state = State::<T>::new();
+pub fn new_state<T>() -> AppState<T>
+where
+    T: Debug + Send + 'static,
+{
+    new_state_with(HashMap::<String, T>::new())
+}
+
+/// Create a new state instance backed by the storage.
+pub fn new_state_with<T, S>(storage: S) -> AppState<T>
+where
+    S: Storage<T> + 'static,
+{
+    let state: State<T> = Box::new(storage);
     Data::new(Mutex::new(state))
 }
 
@@ -18,8 +56,8 @@ pub fn new_state<T>() -> AppState<T> {
 /// Returns None if the entry did not alreay exist (insert operation).
 #[allow(dead_code)]
 pub fn set<T>(data: AppState<T>, key: String, value: T) -> Option<T> {
-    let mut hashmap = data.lock().expect("Could not acquire lock");
-    hashmap.insert(key, value)
+    let mut storage = data.lock().expect("Could not acquire lock");
+    storage.insert(key, value)
 }
 
 /// Get a copy of an application state entry by key.
@@ -29,8 +67,8 @@ pub fn get<T>(data: AppState<T>, key: String) -> Option<T>
 where
     T: Clone,
 {
-    let hashmap = data.lock().expect("Could not acquire lock");
-    Some(hashmap.get(&key)?.to_owned())
+    let storage = data.lock().expect("Could not acquire lock");
+    Some(storage.get(&key)?.to_owned())
 }
 
 /// Get a copy of an application state entry by key.
@@ -40,8 +78,8 @@ pub fn all<T>(data: AppState<T>) -> Option<HashMap<String, T>>
 where
     T: Clone,
 {
-    let hashmap = data.lock().expect("Could not acquire lock");
-    Some(hashmap.clone())
+    let storage = data.lock().expect("Could not acquire lock");
+    Some(storage.entries().clone())
 }
 
 #[allow(dead_code)]
@@ -49,8 +87,8 @@ pub fn values<T>(data: AppState<T>) -> Option<Vec<T>>
 where
     T: Clone,
 {
-    let hashmap = data.lock().expect("Could not acquire lock");
-    let rcds = hashmap.clone().into_values().collect();
+    let storage = data.lock().expect("Could not acquire lock");
+    let rcds = storage.entries().values().cloned().collect();
     Some(rcds)
 }
 
@@ -58,6 +96,6 @@ where
 /// Returns Some(T) only if the entry existed before removal.
 #[allow(dead_code)]
 pub fn delete<T>(data: AppState<T>, key: String) -> Option<T> {
-    let mut hashmap = data.lock().expect("Could not acquire lock");
-    hashmap.remove(&key)
+    let mut storage = data.lock().expect("Could not acquire lock");
+    storage.remove(&key)
 }
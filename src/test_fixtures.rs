@@ -0,0 +1,61 @@
+// Values shared by unit tests, fields not set here are changed by tests with struct update syntax
+use std::collections::HashMap;
+
+use crate::forward_service_models::{MultiMap, ProxyRequestInfo};
+use crate::service::{ApronService, ApronServiceProvider};
+
+pub const TEST_SERVICE_ID: &str = "test_service";
+
+pub fn new_provider(id: &str, base_url: &str) -> ApronServiceProvider {
+    ApronServiceProvider {
+        id: Some(id.to_string()),
+        name: None,
+        desc: None,
+        base_url: Some(base_url.to_string()),
+        schema: Some("http".to_string()),
+        created_at: None,
+        updated_at: None,
+        extra_detail: None,
+        weight: None,
+        health_check: None,
+    }
+}
+
+pub fn new_service(providers: Vec<ApronServiceProvider>) -> ApronService {
+    ApronService {
+        peer_id: None,
+        id: TEST_SERVICE_ID.to_string(),
+        name: None,
+        desc: None,
+        logo: None,
+        usage: None,
+        providers: Some(providers),
+        is_deleted: None,
+        price_plan: None,
+        user_id: None,
+        lb_strategy: None,
+        timeouts: None,
+        retry: None,
+        header_rules: None,
+        version: None,
+        origin_peer_id: None,
+    }
+}
+
+pub fn new_request(http_method: &str) -> ProxyRequestInfo {
+    ProxyRequestInfo {
+        service_id: TEST_SERVICE_ID.to_string(),
+        request_id: "req".to_string(),
+        ver: 1,
+        user_key: "key".to_string(),
+        req_path: "".to_string(),
+        http_method: http_method.to_string(),
+        headers: MultiMap::new(),
+        query_args: MultiMap::new(),
+        json_data: HashMap::new(),
+        form_data: HashMap::new(),
+        raw_body: vec![],
+        has_more_body: false,
+        is_websocket: false,
+    }
+}
@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_CONNECT_SECS: u64 = 10;
+const DEFAULT_UPSTREAM_SECS: u64 = 30;
+// Streaming responses may only start after the first event is ready
+const DEFAULT_STREAMING_UPSTREAM_SECS: u64 = 300;
+// Time allowed for relaying between gateways, on top of connecting and waiting for provider
+const RELAY_MARGIN_SECS: u64 = 5;
+
+/// Timeouts of requests proxied to a service, all fields are optional.
+/// Requests between gateways can't last longer than the protocol request timeout anyway.
+#[derive(Deserialize, Debug, Serialize, PartialEq, Clone, Default)]
+pub struct TimeoutConfig {
+    // Connecting to service side gateway, and from there to provider
+    pub connect_secs: Option<u64>,
+    // Waiting for provider to respond once the request is sent
+    pub upstream_secs: Option<u64>,
+    // Whole request until the response starts, enforced by both gateways
+    pub total_secs: Option<u64>,
+}
+
+impl TimeoutConfig {
+    pub fn connect(&self) -> Duration {
+        Duration::from_secs(self.connect_secs.unwrap_or(DEFAULT_CONNECT_SECS))
+    }
+
+    pub fn upstream(&self, is_streaming: bool) -> Duration {
+        let default = if is_streaming {
+            DEFAULT_STREAMING_UPSTREAM_SECS
+        } else {
+            DEFAULT_UPSTREAM_SECS
+        };
+        Duration::from_secs(self.upstream_secs.unwrap_or(default))
+    }
+
+    pub fn total(&self, is_streaming: bool) -> Duration {
+        match self.total_secs {
+            Some(total_secs) => Duration::from_secs(total_secs),
+            None => {
+                self.connect()
+                    + self.upstream(is_streaming)
+                    + Duration::from_secs(RELAY_MARGIN_SECS)
+            }
+        }
+    }
+}
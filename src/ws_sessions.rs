@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+
+use actix::Addr;
+use futures::channel::mpsc;
+use libp2p::PeerId;
+use log::info;
+
+use crate::forward_service_actors::ServiceSideWsActor;
+use crate::forward_service_models::ProxyData;
+
+/// Client peer and request id of a websocket session, peers can only reach sessions opened by themselves.
+pub type SessionKey = (PeerId, String);
+
+// Ws session connected to provider, frames of provider are taken once client side gateway opens stream of it
+struct WsSession {
+    addr: Addr<ServiceSideWsActor>,
+    frames: Option<mpsc::Receiver<ProxyData>>,
+}
+
+/// Websocket sessions served by service side gateway, kept apart by client peer and request id,
+/// so any number of sessions of one or more peers are isolated.
+#[derive(Default)]
+pub struct WsSessions {
+    sessions: HashMap<SessionKey, WsSession>,
+    // Close received for sessions still connecting to ws service, passed on once connected
+    connecting: HashMap<SessionKey, Vec<ProxyData>>,
+}
+
+impl WsSessions {
+    pub fn connecting(&mut self, session: SessionKey) {
+        self.connecting.insert(session, Vec::new());
+    }
+
+    /// Session connected to ws service gets data received while connecting,
+    /// and is kept unless it is closed already.
+    pub fn connected(
+        &mut self,
+        session: SessionKey,
+        addr: Addr<ServiceSideWsActor>,
+        frames: mpsc::Receiver<ProxyData>,
+    ) {
+        let pending = self.connecting.remove(&session).unwrap_or_default();
+        let is_closed = pending.iter().any(ProxyData::is_close);
+        for data in pending {
+            addr.do_send(data);
+        }
+        if !is_closed {
+            info!(
+                "ServiceSideGateway: Ws session {} of {} connected, {} sessions in total",
+                session.1,
+                session.0,
+                self.sessions.len() + 1
+            );
+            self.sessions.insert(
+                session,
+                WsSession {
+                    addr,
+                    frames: Some(frames),
+                },
+            );
+        }
+    }
+
+    pub fn connect_failed(&mut self, session: &SessionKey) {
+        self.connecting.remove(session);
+    }
+
+    /// Frames of the session to be relayed over the stream opened by client side gateway,
+    /// they can only be taken once.
+    pub fn take_frames(
+        &mut self,
+        session: &SessionKey,
+    ) -> Option<(Addr<ServiceSideWsActor>, mpsc::Receiver<ProxyData>)> {
+        let ws_session = self.sessions.get_mut(session)?;
+        let frames = ws_session.frames.take()?;
+        let addr = ws_session.addr.clone();
+        // Session over before the stream opened is forgotten once its last frames are taken
+        if !addr.connected() {
+            self.sessions.remove(session);
+        }
+        Some((addr, frames))
+    }
+
+    /// Session closed by client side gateway, the close is passed to ws service.
+    pub fn close(&mut self, session: &SessionKey, close: ProxyData) {
+        if let Some(pending) = self.connecting.get_mut(session) {
+            pending.push(close);
+        } else if let Some(ws_session) = self.sessions.remove(session) {
+            info!(
+                "ServiceSideGateway: Ws session {} of {} abandoned",
+                session.1, session.0
+            );
+            ws_session.addr.do_send(close);
+        }
+    }
+
+    /// Session is over, told by its actor.
+    /// Frames not taken yet are still relayed once client side gateway opens stream of the session.
+    pub fn done(&mut self, session: &SessionKey) {
+        if matches!(self.sessions.get(session), Some(ws_session) if ws_session.frames.is_none()) {
+            info!(
+                "ServiceSideGateway: Ws session {} of {} is over, {} sessions left",
+                session.1,
+                session.0,
+                self.sessions.len() - 1
+            );
+            self.sessions.remove(session);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use actix::{Actor, ActorContext, StreamHandler, System};
+    use actix_web::test::{self, TestServer};
+    use actix_web::web::{self, Data};
+    use actix_web::{App, HttpRequest};
+    use actix_web_actors::ws;
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::circuit_breaker::CircuitBreaker;
+    use crate::forward_service_utils::connect_to_ws_service;
+    use crate::load_balancer::{select_provider, ProviderSelector};
+    use crate::service::{ApronServiceProvider, SharedHandler};
+    use crate::test_fixtures::{new_provider, new_request, new_service};
+
+    // Ws service replying text messages as they are
+    struct Echo;
+
+    impl Actor for Echo {
+        type Context = ws::WebsocketContext<Self>;
+    }
+
+    impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Echo {
+        fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+            match msg {
+                Ok(ws::Message::Text(text)) => ctx.text(text),
+                Ok(ws::Message::Close(reason)) => {
+                    ctx.close(reason);
+                    ctx.stop();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn start_echo() -> TestServer {
+        test::start(|| {
+            App::new().default_service(web::to(|req: HttpRequest, stream: web::Payload| {
+                async move { ws::start(Echo, &req, stream) }
+            }))
+        })
+    }
+
+    // Connect to the service the way service side gateway does for a session of client peer
+    async fn connect(
+        server: &TestServer,
+        session: &SessionKey,
+    ) -> (Addr<ServiceSideWsActor>, mpsc::Receiver<ProxyData>) {
+        let service = new_service(vec![ApronServiceProvider {
+            schema: Some("ws".to_string()),
+            ..new_provider("echo", &server.addr().to_string())
+        }]);
+        let provider = select_provider(
+            Data::new(ProviderSelector::default()),
+            &CircuitBreaker::default(),
+            &service,
+            "ws",
+        )
+        .unwrap();
+        let mut req_info = new_request("GET");
+        req_info.request_id = session.1.clone();
+        req_info.is_websocket = true;
+        let p2p_handler = Data::new(SharedHandler {
+            command_sender: Mutex::new(mpsc::channel(0).0),
+        });
+
+        let (addr, frames, handshake) =
+            connect_to_ws_service(provider, &service, session.0, req_info, p2p_handler, None)
+                .await
+                .unwrap();
+        assert_eq!(handshake.status_code, 101);
+        (addr, frames)
+    }
+
+    fn text_frame(request_id: &str, text: &str) -> ProxyData {
+        ProxyData {
+            request_id: request_id.to_string(),
+            is_binary: false,
+            data: text.as_bytes().to_vec(),
+            control: None,
+        }
+    }
+
+    // Request id and text of frame relayed from service
+    async fn next_text(frames: &mut mpsc::Receiver<ProxyData>) -> (String, String) {
+        let frame = frames.next().await.expect("Frame to be relayed.");
+        assert!(frame.control.is_none() && !frame.is_binary);
+        (frame.request_id, String::from_utf8(frame.data).unwrap())
+    }
+
+    fn text(request_id: &str, text: &str) -> (String, String) {
+        (request_id.to_string(), text.to_string())
+    }
+
+    #[test]
+    fn test_sessions_of_one_peer_are_isolated() {
+        System::new("test").block_on(async {
+            let server = start_echo();
+            let peer = PeerId::random();
+            let session_a = (peer, "a".to_string());
+            let session_b = (peer, "b".to_string());
+            let mut sessions = WsSessions::default();
+            for session in &[&session_a, &session_b] {
+                sessions.connecting((*session).clone());
+                let (addr, frames) = connect(&server, session).await;
+                sessions.connected((*session).clone(), addr, frames);
+            }
+
+            // Sessions can only be reached by the peer opened them, once
+            assert!(sessions
+                .take_frames(&(PeerId::random(), "a".to_string()))
+                .is_none());
+            let (addr_a, mut frames_a) = sessions.take_frames(&session_a).unwrap();
+            let (addr_b, mut frames_b) = sessions.take_frames(&session_b).unwrap();
+            assert!(sessions.take_frames(&session_a).is_none());
+
+            addr_b.do_send(text_frame("b", "to b"));
+            addr_a.do_send(text_frame("a", "to a"));
+            assert_eq!(next_text(&mut frames_a).await, text("a", "to a"));
+            assert_eq!(next_text(&mut frames_b).await, text("b", "to b"));
+
+            // Closing one session leaves the other one working
+            sessions.close(
+                &session_a,
+                ProxyData::close("a".to_string(), 1001, "Client is gone".to_string()),
+            );
+            assert!(frames_a.next().await.is_none());
+            addr_b.do_send(text_frame("b", "again"));
+            assert_eq!(next_text(&mut frames_b).await, text("b", "again"));
+        });
+    }
+
+    #[test]
+    fn test_close_while_connecting_only_ends_that_session() {
+        System::new("test").block_on(async {
+            let server = start_echo();
+            let peer = PeerId::random();
+            let session_a = (peer, "a".to_string());
+            let session_b = (peer, "b".to_string());
+            let mut sessions = WsSessions::default();
+            sessions.connecting(session_a.clone());
+            sessions.connecting(session_b.clone());
+
+            sessions.close(
+                &session_a,
+                ProxyData::close("a".to_string(), 1001, "Client is gone".to_string()),
+            );
+            let (addr, frames) = connect(&server, &session_a).await;
+            sessions.connected(session_a.clone(), addr, frames);
+            let (addr, frames) = connect(&server, &session_b).await;
+            sessions.connected(session_b.clone(), addr, frames);
+
+            assert!(sessions.take_frames(&session_a).is_none());
+            let (addr_b, mut frames_b) = sessions.take_frames(&session_b).unwrap();
+            addr_b.do_send(text_frame("b", "to b"));
+            assert_eq!(next_text(&mut frames_b).await, text("b", "to b"));
+        });
+    }
+}
@@ -0,0 +1,621 @@
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::iter;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use actix::Recipient;
+use futures::channel::{mpsc, oneshot};
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use libp2p::core::connection::ConnectionId;
+use libp2p::core::upgrade::{
+    read_length_prefixed, write_length_prefixed, InboundUpgrade, OutboundUpgrade, UpgradeInfo,
+};
+use libp2p::swarm::{
+    KeepAlive, NegotiatedSubstream, NetworkBehaviour, NetworkBehaviourAction, NotifyHandler,
+    PollParameters, ProtocolsHandler, ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr,
+    SubstreamProtocol,
+};
+use libp2p::{Multiaddr, PeerId};
+use log::{info, warn};
+
+use crate::forward_service_models::ProxyData;
+use crate::protocol::MAX_MESSAGE_SIZE;
+
+/// Frames of a websocket session queued for relaying, the session waits while the queue is full.
+pub const WS_FRAME_QUEUE_SIZE: usize = 16;
+
+const WS_STREAM_PROTOCOL: &[u8] = b"/apron/ws/1.0.0";
+// Request ids are generated by client side gateway, and short
+const MAX_REQUEST_ID_SIZE: usize = 256;
+
+type OpenSender = oneshot::Sender<Result<WsStream, String>>;
+
+/// Substream carrying frames of one websocket session in both directions.
+/// The connection to the peer is kept while any stream is alive.
+pub struct WsStream {
+    socket: NegotiatedSubstream,
+    _alive: Arc<()>,
+}
+
+impl fmt::Debug for WsStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WsStream")
+    }
+}
+
+impl AsyncRead for WsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.socket).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for WsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.socket).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.socket).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.socket).poll_close(cx)
+    }
+}
+
+// Opens stream of a session, request id of which is sent first
+pub struct OpenWsStream(String);
+
+// Accepts stream of a session opened by peer
+pub struct AcceptWsStream;
+
+impl UpgradeInfo for OpenWsStream {
+    type Info = &'static [u8];
+    type InfoIter = iter::Once<Self::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        iter::once(WS_STREAM_PROTOCOL)
+    }
+}
+
+impl UpgradeInfo for AcceptWsStream {
+    type Info = &'static [u8];
+    type InfoIter = iter::Once<Self::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        iter::once(WS_STREAM_PROTOCOL)
+    }
+}
+
+impl OutboundUpgrade<NegotiatedSubstream> for OpenWsStream {
+    type Output = NegotiatedSubstream;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, io::Result<NegotiatedSubstream>>;
+
+    fn upgrade_outbound(self, mut socket: NegotiatedSubstream, _: Self::Info) -> Self::Future {
+        async move {
+            write_length_prefixed(&mut socket, self.0.as_bytes()).await?;
+            Ok(socket)
+        }
+        .boxed()
+    }
+}
+
+impl InboundUpgrade<NegotiatedSubstream> for AcceptWsStream {
+    type Output = (String, NegotiatedSubstream);
+    type Error = io::Error;
+    type Future = BoxFuture<'static, io::Result<(String, NegotiatedSubstream)>>;
+
+    fn upgrade_inbound(self, mut socket: NegotiatedSubstream, _: Self::Info) -> Self::Future {
+        async move {
+            let request_id = read_length_prefixed(&mut socket, MAX_REQUEST_ID_SIZE).await?;
+            let request_id = String::from_utf8(request_id)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Ok((request_id, socket))
+        }
+        .boxed()
+    }
+}
+
+/// Handler of websocket streams on one connection.
+pub struct WsStreamHandler {
+    // Streams requested by this gateway, not opened yet
+    pending_opens: VecDeque<(String, OpenSender)>,
+    // Streams being negotiated with peer
+    opening: usize,
+    // Streams opened by peer, waiting to be passed to behaviour
+    inbound: VecDeque<(String, WsStream)>,
+    // Shared by streams handed out, which are still alive while it has other owners
+    alive: Arc<()>,
+}
+
+impl WsStreamHandler {
+    fn stream(&self, socket: NegotiatedSubstream) -> WsStream {
+        WsStream {
+            socket,
+            _alive: self.alive.clone(),
+        }
+    }
+}
+
+impl ProtocolsHandler for WsStreamHandler {
+    type InEvent = (String, OpenSender);
+    type OutEvent = (String, WsStream);
+    type Error = io::Error;
+    type InboundProtocol = AcceptWsStream;
+    type OutboundProtocol = OpenWsStream;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = OpenSender;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<AcceptWsStream, ()> {
+        SubstreamProtocol::new(AcceptWsStream, ())
+    }
+
+    fn inject_fully_negotiated_inbound(
+        &mut self,
+        (request_id, socket): (String, NegotiatedSubstream),
+        _: (),
+    ) {
+        let stream = self.stream(socket);
+        self.inbound.push_back((request_id, stream));
+    }
+
+    fn inject_fully_negotiated_outbound(
+        &mut self,
+        socket: NegotiatedSubstream,
+        sender: OpenSender,
+    ) {
+        self.opening -= 1;
+        let _ = sender.send(Ok(self.stream(socket)));
+    }
+
+    fn inject_event(&mut self, open: (String, OpenSender)) {
+        self.pending_opens.push_back(open);
+    }
+
+    fn inject_dial_upgrade_error(
+        &mut self,
+        sender: OpenSender,
+        error: ProtocolsHandlerUpgrErr<io::Error>,
+    ) {
+        self.opening -= 1;
+        let _ = sender.send(Err(format!("Open websocket stream failed: {:?}", error)));
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        if Arc::strong_count(&self.alive) > 1 || self.opening > 0 || !self.pending_opens.is_empty()
+        {
+            KeepAlive::Yes
+        } else {
+            KeepAlive::No
+        }
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<ProtocolsHandlerEvent<OpenWsStream, OpenSender, (String, WsStream), io::Error>> {
+        if let Some(inbound) = self.inbound.pop_front() {
+            return Poll::Ready(ProtocolsHandlerEvent::Custom(inbound));
+        }
+        if let Some((request_id, sender)) = self.pending_opens.pop_front() {
+            self.opening += 1;
+            return Poll::Ready(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                protocol: SubstreamProtocol::new(OpenWsStream(request_id), sender),
+            });
+        }
+        Poll::Pending
+    }
+}
+
+/// Stream of a websocket session opened by peer.
+#[derive(Debug)]
+pub struct WsStreamEvent {
+    pub peer: PeerId,
+    pub request_id: String,
+    pub stream: WsStream,
+}
+
+/// Websocket sessions relayed between gateways, each one over its own substream,
+/// which delivers frames in order and applies backpressure of the connection.
+#[derive(Default)]
+pub struct WsStreamBehaviour {
+    connected: HashSet<PeerId>,
+    actions: VecDeque<NetworkBehaviourAction<(String, OpenSender), WsStreamEvent>>,
+}
+
+impl WsStreamBehaviour {
+    /// Open stream of a websocket session to connected peer, the stream is returned with sender.
+    pub fn open(&mut self, peer: PeerId, request_id: String, sender: OpenSender) {
+        if !self.connected.contains(&peer) {
+            let _ = sender.send(Err(format!("Peer {} is not connected", peer)));
+            return;
+        }
+        self.actions
+            .push_back(NetworkBehaviourAction::NotifyHandler {
+                peer_id: peer,
+                handler: NotifyHandler::Any,
+                event: (request_id, sender),
+            });
+    }
+}
+
+impl NetworkBehaviour for WsStreamBehaviour {
+    type ProtocolsHandler = WsStreamHandler;
+    type OutEvent = WsStreamEvent;
+
+    fn new_handler(&mut self) -> WsStreamHandler {
+        WsStreamHandler {
+            pending_opens: VecDeque::new(),
+            opening: 0,
+            inbound: VecDeque::new(),
+            alive: Arc::new(()),
+        }
+    }
+
+    fn addresses_of_peer(&mut self, _: &PeerId) -> Vec<Multiaddr> {
+        Vec::new()
+    }
+
+    fn inject_connected(&mut self, peer: &PeerId) {
+        self.connected.insert(*peer);
+    }
+
+    fn inject_disconnected(&mut self, peer: &PeerId) {
+        self.connected.remove(peer);
+    }
+
+    fn inject_event(
+        &mut self,
+        peer: PeerId,
+        _: ConnectionId,
+        (request_id, stream): (String, WsStream),
+    ) {
+        self.actions
+            .push_back(NetworkBehaviourAction::GenerateEvent(WsStreamEvent {
+                peer,
+                request_id,
+                stream,
+            }));
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<(String, OpenSender), WsStreamEvent>> {
+        match self.actions.pop_front() {
+            Some(action) => Poll::Ready(action),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Write frame of a session to stream.
+pub async fn write_frame<W>(writer: &mut W, frame: &ProxyData) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let data =
+        bincode::serialize(frame).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    write_length_prefixed(writer, data).await
+}
+
+/// Read next frame of a session, None is returned once peer finished the stream.
+pub async fn read_frame<R>(reader: &mut R) -> io::Result<Option<ProxyData>>
+where
+    R: AsyncRead + Unpin,
+{
+    let data = read_length_prefixed(reader, MAX_MESSAGE_SIZE).await?;
+    if data.is_empty() {
+        return Ok(None);
+    }
+    bincode::deserialize(&data)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Relay a websocket session over stream. Frames of this side are written in order,
+/// and frames of peer are passed to the session one after another, so a slow session
+/// holds back its peer only. Relaying ends once close frame is passed both ways, or the stream breaks.
+pub async fn relay_ws_session(
+    stream: WsStream,
+    request_id: String,
+    mut frames: mpsc::Receiver<ProxyData>,
+    session: Recipient<ProxyData>,
+) {
+    let (mut reader, mut writer) = stream.split();
+    let sending = async {
+        while let Some(frame) = frames.next().await {
+            let is_close = frame.is_close();
+            if let Err(e) = write_frame(&mut writer, &frame).await {
+                warn!("Write ws frame of {} failed: {}", request_id, e);
+                return;
+            }
+            if is_close {
+                break;
+            }
+        }
+        // Peer sees end of stream after the last frame
+        let _ = writer.close().await;
+    };
+    let receiving = async {
+        loop {
+            match read_frame(&mut reader).await {
+                Ok(Some(frame)) => {
+                    let is_close = frame.is_close();
+                    // Session is stopped already, nothing more to pass
+                    if session.send(frame).await.is_err() || is_close {
+                        return;
+                    }
+                }
+                result => {
+                    if let Err(e) = result {
+                        warn!("Read ws frame of {} failed: {}", request_id, e);
+                    }
+                    // Stream ends without close frame, the session can't go on
+                    let _ = session.do_send(ProxyData::close(
+                        request_id.clone(),
+                        1001,
+                        String::from("Peer gateway is gone"),
+                    ));
+                    return;
+                }
+            }
+        }
+    };
+    future::join(sending, receiving).await;
+    info!("Relaying ws session {} ended", request_id);
+}
+
+/// Pass frames of one side of a session to the other one, while both sides are in this gateway.
+/// The other side is closed if frames end without close frame.
+pub async fn pipe_ws_session(
+    request_id: String,
+    mut frames: mpsc::Receiver<ProxyData>,
+    session: Recipient<ProxyData>,
+) {
+    while let Some(frame) = frames.next().await {
+        let is_close = frame.is_close();
+        if session.send(frame).await.is_err() || is_close {
+            return;
+        }
+    }
+    let _ = session.do_send(ProxyData::close(
+        request_id,
+        1001,
+        String::from("Session is gone"),
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use actix::{Actor, Handler, System};
+    use libp2p::core::transport::MemoryTransport;
+    use libp2p::core::upgrade::Version;
+    use libp2p::identity::Keypair;
+    use libp2p::ping::{Ping, PingConfig, PingEvent};
+    use libp2p::plaintext::PlainText2Config;
+    use libp2p::swarm::{Swarm, SwarmEvent};
+    use libp2p::yamux::YamuxConfig;
+    use libp2p::{NetworkBehaviour, Transport};
+
+    use super::*;
+    use crate::forward_service_models::WsControl;
+
+    // Session which keeps frames passed to it
+    struct Collector(Arc<Mutex<Vec<ProxyData>>>);
+
+    impl Actor for Collector {
+        type Context = actix::Context<Self>;
+    }
+
+    impl Handler<ProxyData> for Collector {
+        type Result = ();
+
+        fn handle(&mut self, frame: ProxyData, _: &mut Self::Context) {
+            self.0.lock().unwrap().push(frame);
+        }
+    }
+
+    // Ping keeps connection alive while no session is relayed, as other behaviours of gateway do
+    #[derive(NetworkBehaviour)]
+    #[behaviour(event_process = false, out_event = "TestEvent")]
+    struct TestBehaviour {
+        ws_stream: WsStreamBehaviour,
+        ping: Ping,
+    }
+
+    enum TestEvent {
+        WsStream(WsStreamEvent),
+        Ping,
+    }
+
+    impl From<WsStreamEvent> for TestEvent {
+        fn from(event: WsStreamEvent) -> Self {
+            TestEvent::WsStream(event)
+        }
+    }
+
+    impl From<PingEvent> for TestEvent {
+        fn from(_: PingEvent) -> Self {
+            TestEvent::Ping
+        }
+    }
+
+    fn new_swarm() -> Swarm<TestBehaviour> {
+        let key = Keypair::generate_ed25519();
+        let peer_id = key.public().into_peer_id();
+        let transport = MemoryTransport
+            .upgrade(Version::V1)
+            .authenticate(PlainText2Config {
+                local_public_key: key.public(),
+            })
+            .multiplex(YamuxConfig::default())
+            .boxed();
+        let behaviour = TestBehaviour {
+            ws_stream: WsStreamBehaviour::default(),
+            ping: Ping::new(PingConfig::new().with_keep_alive(true)),
+        };
+        Swarm::new(transport, behaviour, peer_id)
+    }
+
+    // Connect two gateways and open stream of a session from the first one.
+    // Swarms keep running in background, so the streams stay usable.
+    async fn open_stream(request_id: &str) -> (WsStream, WsStream) {
+        let mut client = new_swarm();
+        let mut service = new_swarm();
+        let service_peer = *service.local_peer_id();
+        service.listen_on("/memory/0".parse().unwrap()).unwrap();
+        let address = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = service.select_next_some().await {
+                break address;
+            }
+        };
+
+        client.dial_addr(address).unwrap();
+        loop {
+            futures::select! {
+                event = client.select_next_some() => {
+                    if let SwarmEvent::ConnectionEstablished { .. } = event {
+                        break;
+                    }
+                }
+                _ = service.select_next_some() => {}
+            }
+        }
+
+        let (sender, opened) = oneshot::channel();
+        client
+            .behaviour_mut()
+            .ws_stream
+            .open(service_peer, request_id.to_string(), sender);
+        let mut opened = opened.fuse();
+        let (mut client_stream, mut service_stream) = (None, None);
+        while client_stream.is_none() || service_stream.is_none() {
+            futures::select! {
+                _ = client.select_next_some() => {}
+                event = service.select_next_some() => {
+                    if let SwarmEvent::Behaviour(TestEvent::WsStream(event)) = event {
+                        assert_eq!(event.request_id, request_id);
+                        service_stream = Some(event.stream);
+                    }
+                }
+                stream = opened => client_stream = Some(stream.unwrap().unwrap()),
+            }
+        }
+
+        async_std::task::spawn(async move {
+            loop {
+                futures::select! {
+                    _ = client.select_next_some() => {}
+                    _ = service.select_next_some() => {}
+                }
+            }
+        });
+        (client_stream.unwrap(), service_stream.unwrap())
+    }
+
+    fn text_frame(request_id: &str, i: usize) -> ProxyData {
+        ProxyData {
+            request_id: request_id.to_string(),
+            is_binary: false,
+            data: i.to_string().into_bytes(),
+            control: None,
+        }
+    }
+
+    #[test]
+    fn test_relay_in_order() {
+        System::new("test").block_on(async {
+            let (client_stream, mut service_stream) = open_stream("req").await;
+            let received = Arc::new(Mutex::new(Vec::new()));
+            let session = Collector(received.clone()).start().recipient();
+            let (mut frames, frames_receiver) = mpsc::channel(WS_FRAME_QUEUE_SIZE);
+
+            let client_side = async {
+                for i in 0..100 {
+                    frames.send(text_frame("req", i)).await.unwrap();
+                }
+                let close = ProxyData::close("req".to_string(), 1000, String::new());
+                frames.send(close).await.unwrap();
+            };
+            let service_side = async {
+                for i in 0..100 {
+                    let frame = read_frame(&mut service_stream).await.unwrap().unwrap();
+                    assert_eq!(frame.data, i.to_string().into_bytes());
+                    write_frame(&mut service_stream, &text_frame("req", i))
+                        .await
+                        .unwrap();
+                }
+                assert!(read_frame(&mut service_stream)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .is_close());
+                // Stream is finished after close frame
+                assert!(read_frame(&mut service_stream).await.unwrap().is_none());
+                let close = ProxyData::close("req".to_string(), 1000, String::new());
+                write_frame(&mut service_stream, &close).await.unwrap();
+            };
+            future::join3(
+                relay_ws_session(client_stream, "req".to_string(), frames_receiver, session),
+                client_side,
+                service_side,
+            )
+            .await;
+
+            let received = received.lock().unwrap();
+            assert_eq!(received.len(), 101);
+            for (i, frame) in received[..100].iter().enumerate() {
+                assert_eq!(frame.data, i.to_string().into_bytes());
+            }
+            assert!(received[100].is_close());
+        });
+    }
+
+    #[test]
+    fn test_relay_closes_session_once_peer_is_gone() {
+        System::new("test").block_on(async {
+            let (client_stream, service_stream) = open_stream("req").await;
+            let received = Arc::new(Mutex::new(Vec::new()));
+            let session = Collector(received.clone()).start();
+            // Session has nothing to send
+            let (_, frames_receiver) = mpsc::channel(WS_FRAME_QUEUE_SIZE);
+
+            drop(service_stream);
+            relay_ws_session(
+                client_stream,
+                "req".to_string(),
+                frames_receiver,
+                session.clone().recipient(),
+            )
+            .await;
+
+            // Frames are handled in order, the close frame is taken once this one is
+            session.send(text_frame("req", 0)).await.unwrap();
+            let received = received.lock().unwrap();
+            assert!(matches!(
+                received[0].control,
+                Some(WsControl::Close {
+                    code: Some(1001),
+                    ..
+                })
+            ));
+        });
+    }
+}